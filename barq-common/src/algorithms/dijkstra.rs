@@ -0,0 +1,346 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use anyhow::Result;
+
+use crate::amount::Msat;
+use crate::node_id::NodeId;
+use crate::short_channel_id::ShortChannelId;
+use crate::strategy::{RouteHop, RouteInput, RouteOutput, Strategy};
+
+/// A routing strategy that finds the cheapest route from the source to the
+/// destination with the Dijkstra shortest-path algorithm.
+///
+/// The search runs backwards, from the destination to the source, so the
+/// amount and the CLTV delay required at each node are known when the
+/// node is reached, and the fee of each hop is computed on the amount it
+/// actually forwards. It only needs the channels and their policies, so it
+/// works on any `NetworkGraph`.
+pub struct Dijkstra;
+
+/// A way we found to reach the destination from a node
+///
+/// A node keeps every label that is not dominated by another one, so a path
+/// pruned by the constraints can not hide a pricier one that honours them.
+struct Label {
+    node_id: NodeId,
+    /// The amount the node must receive to forward the payment
+    amount_msat: Msat,
+    /// The CLTV delay the node must receive
+    delay: u64,
+    /// The number of hops from the node to the destination
+    hops: usize,
+    /// The channel and the label of the node to forward the payment to,
+    /// `None` for the destination
+    next: Option<(ShortChannelId, usize)>,
+    /// Whether a better label of the node was found after this one
+    dominated: bool,
+}
+
+impl Label {
+    /// Whether this label is at least as good as `other` on every resource,
+    /// the number of hops counting only when it is limited
+    fn dominates(&self, other: &Label, count_hops: bool) -> bool {
+        self.amount_msat <= other.amount_msat
+            && self.delay <= other.delay
+            && (!count_hops || self.hops <= other.hops)
+    }
+}
+
+impl Dijkstra {
+    pub fn new() -> Self {
+        Dijkstra
+    }
+}
+
+impl Default for Dijkstra {
+    fn default() -> Self {
+        Dijkstra::new()
+    }
+}
+
+impl Strategy for Dijkstra {
+    /// Determines if the Dijkstra routing strategy can be applied to the given
+    /// input.
+    ///
+    /// This method checks if both the source and the destination nodes are
+    /// in the network graph.
+    fn can_apply(&self, input: &RouteInput) -> Result<bool> {
+        Ok(input.graph.get_node(&input.src_pubkey).is_some()
+            && input.graph.get_node(&input.dest_pubkey).is_some())
+    }
+
+    /// Routes the payment along the path with the lowest total fee, using the
+    /// lowest total CLTV delay to break ties.
+    ///
+    /// Paths exceeding the maximum fee, delay or number of hops of the
+    /// constraints are pruned while searching. Each node keeps all the labels
+    /// that are not dominated on the amount, the delay and, when limited, the
+    /// number of hops, so the cheapest route honouring the constraints is
+    /// found even when a cheaper one does not.
+    fn route(&self, input: &RouteInput) -> Result<RouteOutput> {
+        let constraints = &input.constraints;
+        let max_amount_msat = constraints
+            .max_fee_for(input.amount_msat)
+            .map(|max_fee_msat| input.amount_msat.saturating_add(max_fee_msat));
+        let count_hops = constraints.max_hops.is_some();
+        let mut labels = vec![Label {
+            node_id: input.dest_pubkey,
+            amount_msat: input.amount_msat,
+            delay: input.cltv,
+            hops: 0,
+            next: None,
+            dominated: false,
+        }];
+        // The labels of each node that are not dominated
+        let mut by_node: HashMap<NodeId, Vec<usize>> =
+            HashMap::from([(input.dest_pubkey, vec![0])]);
+        let mut queue = BinaryHeap::new();
+        queue.push(Reverse((input.amount_msat, input.cltv, 0, 0)));
+
+        let mut found = None;
+        while let Some(Reverse((amount_msat, delay, hops, index))) = queue.pop() {
+            // Skip the labels dominated after queueing them
+            if labels[index].dominated {
+                continue;
+            }
+            let node_id = labels[index].node_id;
+            if node_id == input.src_pubkey {
+                found = Some(index);
+                break;
+            }
+            let hops = hops + 1;
+            if constraints.max_hops.is_some_and(|max_hops| hops > max_hops) {
+                continue;
+            }
+            let Some(node) = input.graph.get_node(&node_id) else {
+                continue;
+            };
+
+            for channel in &node.channels {
+                let Some(prev) = channel.counterparty(&node_id) else {
+                    continue;
+                };
+                let Some(policy) = channel.policy_from(&prev) else {
+                    continue;
+                };
+                if input.capacity_from(channel, &prev) < amount_msat
+                    || !policy.can_forward(amount_msat)
+                    || !input.allows_hop(&channel.short_channel_id, &prev, &node_id)
+                {
+                    continue;
+                }
+
+                // We do not pay any fee to ourselves for the first hop
+                let (prev_amount_msat, prev_delay) = if prev == input.src_pubkey {
+                    (amount_msat, delay)
+                } else {
+                    (
+                        amount_msat.saturating_add(policy.fee_msat(amount_msat)),
+                        delay.saturating_add(policy.delay),
+                    )
+                };
+                if max_amount_msat.is_some_and(|max_amount_msat| prev_amount_msat > max_amount_msat)
+                    || constraints
+                        .max_cltv
+                        .is_some_and(|max_cltv| prev_delay > max_cltv)
+                {
+                    continue;
+                }
+                let label = Label {
+                    node_id: prev,
+                    amount_msat: prev_amount_msat,
+                    delay: prev_delay,
+                    hops,
+                    next: Some((channel.short_channel_id, index)),
+                    dominated: false,
+                };
+                let kept = by_node.entry(prev).or_default();
+                if kept
+                    .iter()
+                    .any(|kept| labels[*kept].dominates(&label, count_hops))
+                {
+                    continue;
+                }
+                kept.retain(|kept| {
+                    let dominated = label.dominates(&labels[*kept], count_hops);
+                    labels[*kept].dominated |= dominated;
+                    !dominated
+                });
+                kept.push(labels.len());
+                queue.push(Reverse((prev_amount_msat, prev_delay, hops, labels.len())));
+                labels.push(label);
+            }
+        }
+
+        // Walk the labels forward from the source to build the route
+        let mut path = Vec::new();
+        let mut current = found.and_then(|index| labels[index].next);
+        while let Some((channel, index)) = current {
+            let label = &labels[index];
+            path.push(RouteHop::new(
+                label.node_id,
+                channel,
+                label.delay as u32,
+                label.amount_msat,
+            ));
+            current = label.next;
+        }
+
+        if path.is_empty() {
+            anyhow::bail!(
+                "No route with capacity `{}` to `{}` found",
+                input.amount_msat,
+                input.dest_pubkey
+            );
+        }
+        let output = RouteOutput::single_part(input.amount_msat, path);
+        input.check_constraints(&output)?;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::algorithms::test_utils::{local_channel, node, route_input, scid, TestGraph};
+
+    #[test]
+    fn test_cheapest_route() {
+        let mut graph = TestGraph::new();
+        // alice -> bob -> dave is shorter but more expensive than
+        // alice -> carol -> erin -> dave
+        graph.add_channel("1x1x0", "alice", "bob", 1_000_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel(
+            "2x1x0",
+            "bob",
+            "dave",
+            1_000_000,
+            (40, 5000, 100),
+            (6, 0, 0),
+        );
+        graph.add_channel("3x1x0", "alice", "carol", 1_000_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel(
+            "4x1x0",
+            "carol",
+            "erin",
+            1_000_000,
+            (10, 1000, 10),
+            (6, 0, 0),
+        );
+        graph.add_channel("5x1x0", "erin", "dave", 1_000_000, (20, 1000, 1), (6, 0, 0));
+
+        let input = route_input(graph, "alice", "dave", 100_000, 18);
+        let output = Dijkstra::new().route(&input).unwrap();
+
+        // erin charges 1000 + 100_000 * 1 / 1_000_000 = 1000 to forward
+        // 100_000 msat, carol charges 1000 + 101_000 * 10 / 1_000_000 = 1001
+        assert_eq!(
+            output.parts[0].path,
+            vec![
+                RouteHop::new(node("carol"), scid("3x1x0"), 48, Msat::new(102_001)),
+                RouteHop::new(node("erin"), scid("4x1x0"), 38, Msat::new(101_000)),
+                RouteHop::new(node("dave"), scid("5x1x0"), 18, Msat::new(100_000)),
+            ]
+        );
+        assert_eq!(output.fee_msat(), Msat::new(2001));
+    }
+
+    #[test]
+    fn test_respects_direction_and_capacity() {
+        let mut graph = TestGraph::new();
+        graph.add_channel("1x1x0", "alice", "bob", 1_000_000, (6, 0, 0), (6, 0, 0));
+        // bob -> carol is disabled, carol -> bob is fine
+        graph.add_channel("2x1x0", "bob", "carol", 1_000_000, (6, 0, 0), (6, 0, 0));
+        graph.disable("2x1x0", "bob");
+        graph.add_channel("3x1x0", "alice", "carol", 50_000, (6, 0, 0), (6, 0, 0));
+
+        let input = route_input(graph, "alice", "carol", 100_000, 18);
+        assert!(Dijkstra::new().route(&input).is_err());
+    }
+
+    #[test]
+    fn test_uses_local_balance() {
+        let mut graph = TestGraph::new();
+        graph.add_channel("1x1x0", "alice", "bob", 1_000_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("2x1x0", "bob", "dave", 1_000_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("3x1x0", "alice", "carol", 1_000_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("4x1x0", "carol", "dave", 1_000_000, (6, 1000, 0), (6, 0, 0));
+
+        // The capacity of alice -> bob is on the side of bob
+        let mut input = route_input(graph, "alice", "dave", 100_000, 18);
+        input.local_channels = Arc::new(
+            [
+                local_channel("1x1x0", "bob", 50_000),
+                local_channel("3x1x0", "carol", 500_000),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let output = Dijkstra::new().route(&input).unwrap();
+        assert_eq!(output.parts[0].path[0].id, node("carol"));
+    }
+
+    #[test]
+    fn test_respects_constraints() {
+        let mut graph = TestGraph::new();
+        graph.add_channel("1x1x0", "alice", "bob", 1_000_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("2x1x0", "bob", "dave", 1_000_000, (40, 1000, 0), (6, 0, 0));
+        graph.add_channel("3x1x0", "alice", "carol", 1_000_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel(
+            "4x1x0",
+            "carol",
+            "dave",
+            1_000_000,
+            (10, 2000, 0),
+            (6, 0, 0),
+        );
+
+        // The cheapest route goes through bob
+        let mut input = route_input(graph, "alice", "dave", 100_000, 18);
+        input.constraints.excluded_nodes.insert(node("bob"));
+        let output = Dijkstra::new().route(&input).unwrap();
+        assert_eq!(output.parts[0].path[0].id, node("carol"));
+
+        // Going through carol costs 2% of the amount
+        input.constraints.max_fee_percent = Some(1.0);
+        assert!(Dijkstra::new().route(&input).is_err());
+
+        input.constraints.excluded_nodes.clear();
+        input.constraints.max_fee_percent = None;
+        input.constraints.max_cltv = Some(50);
+        let output = Dijkstra::new().route(&input).unwrap();
+        assert_eq!(output.parts[0].path[0].id, node("carol"));
+
+        input.constraints.max_hops = Some(1);
+        assert!(Dijkstra::new().route(&input).is_err());
+    }
+
+    #[test]
+    fn test_pricier_route_within_max_hops() {
+        let mut graph = TestGraph::new();
+        graph.add_channel("1x1x0", "alice", "carol", 1_000_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("2x1x0", "carol", "dave", 1_000_000, (6, 5000, 0), (6, 0, 0));
+        graph.add_channel("3x1x0", "carol", "erin", 1_000_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("4x1x0", "erin", "dave", 1_000_000, (6, 1000, 0), (6, 0, 0));
+
+        // The cheapest route goes through erin, in 3 hops
+        let mut input = route_input(graph, "alice", "dave", 100_000, 18);
+        let output = Dijkstra::new().route(&input).unwrap();
+        assert_eq!(output.parts[0].path.len(), 3);
+        assert_eq!(output.fee_msat(), Msat::new(1000));
+
+        // The label of carol through erin must not hide the direct channel
+        input.constraints.max_hops = Some(2);
+        let output = Dijkstra::new().route(&input).unwrap();
+        assert_eq!(
+            output.parts[0].path,
+            vec![
+                RouteHop::new(node("carol"), scid("1x1x0"), 24, Msat::new(105_000)),
+                RouteHop::new(node("dave"), scid("2x1x0"), 18, Msat::new(100_000)),
+            ]
+        );
+    }
+}
@@ -29,7 +29,7 @@ impl Strategy for Direct {
     /// This method checks if the destination node is directly connected to the
     /// source node within the network graph.
     fn can_apply(&self, input: &RouteInput) -> Result<bool> {
-        let source = input.src_pubkey.clone();
+        let source = input.src_pubkey;
         let node = input
             .graph
             .get_node(&source)
@@ -49,7 +49,7 @@ impl Strategy for Direct {
     /// This method constructs a route with a single hop if a direct connection
     /// exists between the source and destination nodes.
     fn route(&self, input: &RouteInput) -> Result<RouteOutput> {
-        let source = input.src_pubkey.clone();
+        let source = input.src_pubkey;
         let node = input
             .graph
             .get_node(&source)
@@ -65,9 +65,18 @@ impl Strategy for Direct {
             anyhow::bail!("No channel with `{}` found", input.dest_pubkey);
         }
 
+        // Our own policy does not charge us any fee, but the channel must be
+        // enabled and accept the amount in our direction.
         let channels = channels
             .iter()
-            .filter(|c| c.capacity >= input.amount_msat)
+            .filter(|c| input.capacity_from(c, &input.src_pubkey) >= input.amount_msat)
+            .filter(|c| {
+                input.allows_hop(&c.short_channel_id, &input.src_pubkey, &input.dest_pubkey)
+            })
+            .filter(|c| {
+                c.policy_from(&input.src_pubkey)
+                    .is_none_or(|policy| policy.can_forward(input.amount_msat))
+            })
             .collect::<Vec<_>>();
         let Some(channel) = channels.first() else {
             anyhow::bail!(
@@ -78,12 +87,14 @@ impl Strategy for Direct {
         };
 
         let hop = RouteHop::new(
-            input.dest_pubkey.clone(),
-            channel.short_channel_id.clone(),
+            input.dest_pubkey,
+            channel.short_channel_id,
             input.cltv as u32,
             input.amount_msat,
         );
 
-        Ok(RouteOutput { path: vec![hop] })
+        let output = RouteOutput::single_part(input.amount_msat, vec![hop]);
+        input.check_constraints(&output)?;
+        Ok(output)
     }
 }
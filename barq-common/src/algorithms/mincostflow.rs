@@ -0,0 +1,594 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use anyhow::Result;
+
+use crate::amount::Msat;
+use crate::graph::Channel;
+use crate::node_id::NodeId;
+use crate::short_channel_id::ShortChannelId;
+use crate::strategy::{RouteInput, RouteOutput, RoutePart, Strategy};
+
+/// Maximum number of units the payment amount is split into
+const MAX_UNITS: u64 = 100;
+/// Number of linear pieces approximating the uncertainty cost of a channel
+const PIECES: u64 = 4;
+/// Scale of the uncertainty cost, in msat of fee
+const UNCERTAINTY_SCALE: f64 = 1_000_000.0;
+/// Resolution of the costs, in thousandths of msat, so the fee of a small
+/// unit is not rounded away when the costs are made integers
+const COST_RESOLUTION: f64 = 1_000.0;
+
+/// A routing strategy that splits the payment in multiple parts by solving a
+/// min-cost flow problem, as described by Pickhardt and Richter in "Optimally
+/// Reliable & Cheap Payment Flows on the Lightning Network".
+///
+/// Each direction of a channel is an arc of a flow network. The cost of
+/// sending flow through an arc combines the fee of the channel with the
+/// uncertainty about its liquidity. The fee is linearised: the base fee is
+/// shared by all the units of the payment, as if they all went through the
+/// channel, on top of the proportional fee of each unit. Without any other
+/// knowledge, the liquidity is assumed to be uniformly distributed in
+/// `[0, capacity]`, so the probability of forwarding `x` is
+/// `(capacity + 1 - x) / (capacity + 1)`, and its negative logarithm is the
+/// uncertainty cost. This cost is convex, and it is approximated by a few
+/// linear pieces so the problem can be solved with the successive shortest
+/// paths algorithm.
+///
+/// When the `LiquidityStore` knows that the liquidity is in `[min, max]`,
+/// the arc can carry at most `max` and the probability of forwarding `x` is
+/// conditioned on that knowledge, so forwarding up to `min` is free.
+///
+/// The resulting flow is decomposed in paths, each one becoming a part of
+/// the payment.
+pub struct MinCostFlow {
+    /// Weight of the fee cost against the uncertainty cost
+    mu: u64,
+}
+
+/// An arc of the residual flow network
+struct FlowArc {
+    to: usize,
+    /// Remaining capacity, in units
+    capacity: u64,
+    /// Cost of sending a unit through the arc
+    cost: i64,
+    /// The channel this arc models, `None` for the residual arcs
+    channel: Option<usize>,
+}
+
+/// The flow network built from the network graph
+struct FlowNetwork<'a> {
+    nodes: HashMap<NodeId, usize>,
+    channels: Vec<&'a Channel>,
+    arcs: Vec<FlowArc>,
+    /// The arcs leaving each node
+    adjacency: Vec<Vec<usize>>,
+}
+
+impl<'a> FlowNetwork<'a> {
+    fn new() -> Self {
+        FlowNetwork {
+            nodes: HashMap::new(),
+            channels: Vec::new(),
+            arcs: Vec::new(),
+            adjacency: Vec::new(),
+        }
+    }
+
+    fn node(&mut self, id: NodeId) -> usize {
+        if let Some(index) = self.nodes.get(&id) {
+            return *index;
+        }
+        let index = self.adjacency.len();
+        self.nodes.insert(id, index);
+        self.adjacency.push(Vec::new());
+        index
+    }
+
+    /// Adds an arc together with its residual arc. The residual arc of arc
+    /// `i` is always `i ^ 1`.
+    fn add_arc(&mut self, from: usize, to: usize, capacity: u64, cost: i64, channel: usize) {
+        self.adjacency[from].push(self.arcs.len());
+        self.arcs.push(FlowArc {
+            to,
+            capacity,
+            cost,
+            channel: Some(channel),
+        });
+        self.adjacency[to].push(self.arcs.len());
+        self.arcs.push(FlowArc {
+            to: from,
+            capacity: 0,
+            cost: -cost,
+            channel: None,
+        });
+    }
+
+    /// Finds the cheapest path with residual capacity from `src` to `dest`
+    /// with the Dijkstra algorithm on the reduced costs, and updates the node
+    /// potentials. Returns the arc used to reach each node.
+    fn shortest_path(
+        &self,
+        src: usize,
+        dest: usize,
+        potentials: &mut [i64],
+    ) -> Option<Vec<Option<usize>>> {
+        let mut distances = vec![i64::MAX; self.adjacency.len()];
+        let mut previous = vec![None; self.adjacency.len()];
+        let mut queue = BinaryHeap::new();
+
+        distances[src] = 0;
+        queue.push(Reverse((0, src)));
+        while let Some(Reverse((distance, node))) = queue.pop() {
+            if distance > distances[node] {
+                continue;
+            }
+            for &index in &self.adjacency[node] {
+                let arc = &self.arcs[index];
+                if arc.capacity == 0 {
+                    continue;
+                }
+                let reduced_cost = arc.cost + potentials[node] - potentials[arc.to];
+                let candidate = distance + reduced_cost;
+                if candidate < distances[arc.to] {
+                    distances[arc.to] = candidate;
+                    previous[arc.to] = Some(index);
+                    queue.push(Reverse((candidate, arc.to)));
+                }
+            }
+        }
+
+        if distances[dest] == i64::MAX {
+            return None;
+        }
+        for (potential, distance) in potentials.iter_mut().zip(distances) {
+            if distance != i64::MAX {
+                *potential += distance;
+            }
+        }
+        Some(previous)
+    }
+
+    /// Sends `units` from `src` to `dest` along the cheapest paths.
+    ///
+    /// Returns the number of units that could be sent.
+    fn min_cost_flow(&mut self, src: usize, dest: usize, units: u64) -> u64 {
+        let mut potentials = vec![0; self.adjacency.len()];
+        let mut sent = 0;
+        while sent < units {
+            let Some(previous) = self.shortest_path(src, dest, &mut potentials) else {
+                break;
+            };
+
+            // Find the bottleneck of the path, then push the flow
+            let mut bottleneck = units - sent;
+            let mut node = dest;
+            while let Some(index) = previous[node] {
+                bottleneck = bottleneck.min(self.arcs[index].capacity);
+                node = self.arcs[index ^ 1].to;
+            }
+            let mut node = dest;
+            while let Some(index) = previous[node] {
+                self.arcs[index].capacity -= bottleneck;
+                self.arcs[index ^ 1].capacity += bottleneck;
+                node = self.arcs[index ^ 1].to;
+            }
+            sent += bottleneck;
+        }
+        sent
+    }
+
+    /// Decomposes the flow in paths from `src` to `dest`, returning the
+    /// channels of each path with the units it carries.
+    ///
+    /// The cycles of the flow, that cost nothing, are cancelled on the way.
+    fn decompose(&mut self, src: usize, dest: usize) -> Result<Vec<(Vec<&'a Channel>, u64)>> {
+        let mut paths: Vec<(Vec<&'a Channel>, u64)> = Vec::new();
+        'paths: loop {
+            // Follow the arcs carrying flow, that is the ones whose residual
+            // arc has capacity.
+            let mut arcs = Vec::new();
+            // Where each node shows up in `arcs`
+            let mut visited = HashMap::new();
+            let mut node = src;
+            while node != dest {
+                if let Some(start) = visited.insert(node, arcs.len()) {
+                    self.take_flow(&arcs[start..]);
+                    continue 'paths;
+                }
+                let Some(index) = self.adjacency[node].iter().copied().find(|index| {
+                    self.arcs[*index].channel.is_some() && self.arcs[index ^ 1].capacity > 0
+                }) else {
+                    if arcs.is_empty() {
+                        return Ok(paths);
+                    }
+                    anyhow::bail!("The flow does not reach the destination");
+                };
+                arcs.push(index);
+                node = self.arcs[index].to;
+            }
+
+            let units = self.take_flow(&arcs);
+            // SAFETY: we only follow the arcs modelling a channel.
+            let channels = arcs
+                .iter()
+                .map(|index| self.channels[self.arcs[*index].channel.unwrap()])
+                .collect::<Vec<_>>();
+            // The pieces of the same channel are different arcs, so the same
+            // path can show up more than once.
+            let known = paths
+                .iter_mut()
+                .find(|(known, _): &&mut (Vec<&Channel>, u64)| {
+                    known
+                        .iter()
+                        .map(|channel| &channel.short_channel_id)
+                        .eq(channels.iter().map(|channel| &channel.short_channel_id))
+                });
+            match known {
+                Some((_, known_units)) => *known_units += units,
+                None => paths.push((channels, units)),
+            }
+        }
+    }
+
+    /// Removes from `arcs` the largest flow they all carry, and returns it
+    fn take_flow(&mut self, arcs: &[usize]) -> u64 {
+        let units = arcs
+            .iter()
+            .map(|index| self.arcs[index ^ 1].capacity)
+            .min()
+            .unwrap_or_default();
+        for index in arcs {
+            self.arcs[index ^ 1].capacity -= units;
+        }
+        units
+    }
+}
+
+impl MinCostFlow {
+    pub fn new() -> Self {
+        MinCostFlow { mu: 1 }
+    }
+
+    /// Sets the weight of the fee cost against the uncertainty cost; a
+    /// higher value prefers cheaper routes over more reliable ones.
+    pub fn with_mu(mut self, mu: u64) -> Self {
+        self.mu = mu;
+        self
+    }
+
+    /// Uncertainty cost of sending `x` units through a channel whose
+    /// liquidity is known to be between `min` and `max` units.
+    fn uncertainty(x: u64, min: u64, max: u64) -> f64 {
+        if x <= min {
+            return 0.0;
+        }
+        -(((max + 1 - x) as f64) / ((max + 1 - min) as f64)).ln()
+    }
+
+    /// Builds the flow network of the graph, where each unit of flow is
+    /// `unit_msat`.
+    ///
+    /// With `single_path`, only the channels able to carry the whole payment
+    /// are kept, so the flow can not be split.
+    fn build_network<'a>(
+        &self,
+        input: &'a RouteInput,
+        unit_msat: u64,
+        single_path: bool,
+    ) -> FlowNetwork<'a> {
+        let units = input.amount_msat.msat().div_ceil(unit_msat);
+        let mut network = FlowNetwork::new();
+        for channel in input.graph.get_channels() {
+            if channel.capacity.msat() / unit_msat == 0 {
+                continue;
+            }
+            let index = network.channels.len();
+            network.channels.push(channel);
+
+            for (from, to) in [
+                (&channel.node1, &channel.node2),
+                (&channel.node2, &channel.node1),
+            ] {
+                let Some(policy) = channel.policy_from(from) else {
+                    continue;
+                };
+                if policy.disabled || !input.allows_hop(&channel.short_channel_id, from, to) {
+                    continue;
+                }
+                // We do not pay any fee to ourselves for the first hop
+                let fee_msat = if *from == input.src_pubkey {
+                    0.0
+                } else {
+                    policy.base_fee_millisatoshi.msat() as f64 / units as f64
+                        + (unit_msat * policy.fee_per_millionth) as f64 / 1_000_000.0
+                };
+
+                let (min_msat, max_msat) = input.liquidity_bounds(channel, from, to);
+                let capacity = max_msat.msat() / unit_msat;
+                if capacity == 0 {
+                    continue;
+                }
+                let min = min_msat.msat() / unit_msat;
+
+                let pieces = if single_path {
+                    // The channel carries the whole payment or nothing
+                    if capacity < units {
+                        continue;
+                    }
+                    vec![(0, units)]
+                } else {
+                    // Split the capacity in pieces with increasing uncertainty
+                    // cost, the last piece takes the remainder.
+                    let piece = (capacity / PIECES).max(1);
+                    let mut pieces = Vec::new();
+                    let mut start = 0;
+                    while start < capacity {
+                        let end = if start + 2 * piece > capacity {
+                            capacity
+                        } else {
+                            start + piece
+                        };
+                        pieces.push((start, end));
+                        start = end;
+                    }
+                    pieces
+                };
+
+                let from = network.node(*from);
+                let to = network.node(*to);
+                for (start, end) in pieces {
+                    let uncertainty = (Self::uncertainty(end, min, capacity)
+                        - Self::uncertainty(start, min, capacity))
+                        / (end - start) as f64;
+                    let cost = (self.mu as f64 * fee_msat + uncertainty * UNCERTAINTY_SCALE)
+                        * COST_RESOLUTION;
+                    network.add_arc(from, to, end - start, cost.ceil() as i64, index);
+                }
+            }
+        }
+        network
+    }
+}
+
+impl Default for MinCostFlow {
+    fn default() -> Self {
+        MinCostFlow::new()
+    }
+}
+
+impl Strategy for MinCostFlow {
+    /// Determines if the min-cost flow routing strategy can be applied to the
+    /// given input.
+    ///
+    /// This method checks if both the source and the destination nodes are
+    /// in the network graph.
+    fn can_apply(&self, input: &RouteInput) -> Result<bool> {
+        Ok(input.graph.get_node(&input.src_pubkey).is_some()
+            && input.graph.get_node(&input.dest_pubkey).is_some())
+    }
+
+    /// Routes the payment along the min-cost flow, with one part for each
+    /// path of the flow.
+    ///
+    /// When the payment can not be split, the flow takes the cheapest path
+    /// able to carry the whole amount.
+    fn route(&self, input: &RouteInput) -> Result<RouteOutput> {
+        let amount_msat = input.amount_msat.msat();
+        if amount_msat == 0 {
+            anyhow::bail!("Unable to route a payment of 0 msat");
+        }
+        // The flow is computed on plain numbers of units of `unit_msat`
+        let unit_msat = amount_msat.div_ceil(MAX_UNITS);
+        let units = amount_msat.div_ceil(unit_msat);
+
+        let single_path = input.constraints.max_parts == Some(1);
+        let mut network = self.build_network(input, unit_msat, single_path);
+        let (Some(src), Some(dest)) = (
+            network.nodes.get(&input.src_pubkey).copied(),
+            network.nodes.get(&input.dest_pubkey).copied(),
+        ) else {
+            anyhow::bail!(
+                "No channel with capacity `{}` to `{}` found",
+                unit_msat,
+                input.dest_pubkey
+            );
+        };
+
+        let sent = network.min_cost_flow(src, dest, units);
+        if sent < units {
+            anyhow::bail!(
+                "Not enough capacity to send `{}` to `{}`",
+                input.amount_msat,
+                input.dest_pubkey
+            );
+        }
+
+        let mut paths = network.decompose(src, dest)?;
+        if let Some(max_parts) = input.constraints.max_parts {
+            if paths.len() > max_parts {
+                anyhow::bail!(
+                    "The flow needs `{}` parts, more than the maximum of `{max_parts}` parts",
+                    paths.len()
+                );
+            }
+        }
+        // The units can exceed the amount by less than a unit, we take the
+        // excess from the biggest part.
+        paths.sort_by_key(|(_, units)| Reverse(*units));
+        let mut excess_msat = units * unit_msat - amount_msat;
+        let mut parts = Vec::with_capacity(paths.len());
+        // The flow only carries the amounts delivered, the fees on top of them
+        // must still fit the channels
+        let mut used: HashMap<(ShortChannelId, NodeId), Msat> = HashMap::new();
+        for (channels, units) in paths {
+            let part_msat = Msat::new(units * unit_msat - excess_msat);
+            excess_msat = 0;
+            let part =
+                RoutePart::from_channels(&input.src_pubkey, &channels, part_msat, input.cltv)?;
+            let mut from = input.src_pubkey;
+            for (channel, hop) in channels.iter().zip(&part.path) {
+                let used = used.entry((channel.short_channel_id, from)).or_default();
+                *used = used.saturating_add(hop.amount_msat);
+                let (_, max_msat) = input.liquidity_bounds(channel, &from, &hop.id);
+                if *used > max_msat {
+                    anyhow::bail!(
+                        "Channel `{}` can not forward the fees on top of `{}`",
+                        channel.short_channel_id,
+                        input.amount_msat
+                    );
+                }
+                from = hop.id;
+            }
+            parts.push(part);
+        }
+        let output = RouteOutput { parts };
+        if output.amount_msat() != input.amount_msat {
+            anyhow::bail!(
+                "The flow delivers `{}` instead of `{}`",
+                output.amount_msat(),
+                input.amount_msat
+            );
+        }
+        input.check_constraints(&output)?;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::algorithms::test_utils::{node, route_input, scid, TestGraph};
+    use crate::liquidity::LiquidityStore;
+
+    #[test]
+    fn test_split_payment() {
+        let mut graph = TestGraph::new();
+        // Neither alice -> bob -> dave nor alice -> carol -> dave can carry
+        // the whole payment alone
+        graph.add_channel("1x1x0", "alice", "bob", 600_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("2x1x0", "bob", "dave", 600_000, (6, 1000, 10), (6, 0, 0));
+        graph.add_channel("3x1x0", "alice", "carol", 600_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("4x1x0", "carol", "dave", 600_000, (6, 1000, 10), (6, 0, 0));
+
+        let input = route_input(graph, "alice", "dave", 1_000_000, 18);
+        let output = MinCostFlow::new().route(&input).unwrap();
+
+        assert_eq!(output.parts.len(), 2);
+        assert_eq!(output.amount_msat(), Msat::new(1_000_000));
+        for part in &output.parts {
+            assert!(part.amount_msat <= Msat::new(600_000));
+            assert_eq!(part.path.len(), 2);
+            assert_eq!(part.path[1].id, node("dave"));
+            assert_eq!(part.path[1].delay, 18);
+            assert_eq!(part.path[1].amount_msat, part.amount_msat);
+        }
+    }
+
+    #[test]
+    fn test_max_parts() {
+        let mut graph = TestGraph::new();
+        graph.add_channel("1x1x0", "alice", "bob", 600_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("2x1x0", "bob", "dave", 600_000, (6, 1000, 10), (6, 0, 0));
+        graph.add_channel("3x1x0", "alice", "carol", 600_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("4x1x0", "carol", "dave", 600_000, (6, 1000, 10), (6, 0, 0));
+
+        let mut input = route_input(graph, "alice", "dave", 1_000_000, 18);
+        input.constraints.max_parts = Some(1);
+        assert!(MinCostFlow::new().route(&input).is_err());
+
+        input.amount_msat = Msat::new(300_000);
+        let output = MinCostFlow::new().route(&input).unwrap();
+        assert_eq!(output.parts.len(), 1);
+    }
+
+    #[test]
+    fn test_uses_liquidity_bounds() {
+        let mut graph = TestGraph::new();
+        graph.add_channel("1x1x0", "alice", "bob", 600_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("2x1x0", "bob", "dave", 600_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("3x1x0", "alice", "carol", 600_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("4x1x0", "carol", "dave", 600_000, (6, 1000, 10), (6, 0, 0));
+
+        // bob -> dave is cheaper, but we know it can not forward anything
+        let mut input = route_input(graph, "alice", "dave", 300_000, 18);
+        let liquidity = LiquidityStore::default();
+        liquidity.record_failure(&scid("2x1x0"), &node("bob"), &node("dave"), Msat::new(1));
+        input.liquidity = Arc::new(liquidity);
+        let output = MinCostFlow::new().route(&input).unwrap();
+
+        assert_eq!(output.parts.len(), 1);
+        let part = &output.parts[0];
+        assert_eq!(part.path[0].id, node("carol"));
+        assert_eq!(part.amount_msat, Msat::new(300_000));
+    }
+
+    #[test]
+    fn test_small_payment_fees() {
+        let mut graph = TestGraph::new();
+        // bob charges a base fee, carol a proportional fee below 1 msat per
+        // unit of the payment, on a slightly smaller channel
+        graph.add_channel("1x1x0", "alice", "bob", 1_000_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("2x1x0", "bob", "dave", 1_000_000, (6, 5000, 0), (6, 0, 0));
+        graph.add_channel("3x1x0", "alice", "carol", 990_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("4x1x0", "carol", "dave", 990_000, (6, 0, 1900), (6, 0, 0));
+
+        let input = route_input(graph, "alice", "dave", 50_000, 18);
+        let output = MinCostFlow::new().route(&input).unwrap();
+
+        assert_eq!(output.parts.len(), 1);
+        assert_eq!(output.parts[0].path[0].id, node("carol"));
+        assert_eq!(output.fee_msat(), Msat::new(95));
+    }
+
+    #[test]
+    fn test_decompose_cycle() {
+        let channels = (1..=5)
+            .map(|block| {
+                Channel::new(
+                    scid(&format!("{block}x1x0")),
+                    node("alice"),
+                    node("bob"),
+                    Msat::new(1_000),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut network = FlowNetwork::new();
+        let (src, a, b, dest) = (0, 1, 2, 3);
+        for id in ["src", "a", "b", "dest"] {
+            network.node(node(id));
+        }
+        network.channels = channels.iter().collect();
+        // 2 units reach the destination, with a cycle between a and b
+        for (index, (from, to, units)) in [
+            (src, a, 2),
+            (a, b, 2),
+            (b, a, 1),
+            (a, dest, 1),
+            (b, dest, 1),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            network.add_arc(from, to, 0, 0, index);
+            network.arcs[2 * index + 1].capacity = units;
+        }
+
+        let paths = network.decompose(src, dest).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths.iter().map(|(_, units)| units).sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn test_not_enough_capacity() {
+        let mut graph = TestGraph::new();
+        graph.add_channel("1x1x0", "alice", "bob", 600_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("2x1x0", "bob", "dave", 600_000, (6, 1000, 10), (6, 0, 0));
+
+        let input = route_input(graph, "alice", "dave", 1_000_000, 18);
+        assert!(MinCostFlow::new().route(&input).is_err());
+    }
+}
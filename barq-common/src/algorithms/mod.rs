@@ -1,2 +1,7 @@
+pub mod dijkstra;
 pub mod direct;
+pub mod mincostflow;
 pub mod probabilistic;
+
+#[cfg(test)]
+pub(crate) mod test_utils;
@@ -1,48 +1,81 @@
 use core::ops::Deref;
-use std::str::FromStr;
 use std::sync::Arc;
 
 use anyhow::Result;
 use reqwest::blocking;
 
-use lampo_common::bitcoin::secp256k1::PublicKey;
 use lampo_common::conf::Network;
-use lampo_common::ldk::ln::msgs::ChannelAnnouncement;
-use lampo_common::ldk::routing::gossip::NetworkGraph as LdkNetworkGraph;
-use lampo_common::ldk::routing::router::{find_route, PaymentParameters, Route, RouteParameters};
-use lampo_common::ldk::routing::scoring::{
-    ProbabilisticScorer, ProbabilisticScoringDecayParameters, ProbabilisticScoringFeeParameters,
+use lampo_common::ldk::ln::channelmanager::{
+    provided_init_features, ChannelCounterparty, ChannelDetails,
 };
+use lampo_common::ldk::ln::msgs::{ChannelAnnouncement, ChannelUpdate, NodeAnnouncement};
+use lampo_common::ldk::ln::ChannelId;
+use lampo_common::ldk::routing::gossip::{NetworkGraph as LdkNetworkGraph, RoutingFees};
+use lampo_common::ldk::routing::router::{
+    find_route, InFlightHtlcs as LdkInFlightHtlcs, Path, PaymentParameters, Route,
+    RouteHint as LdkRouteHint, RouteHintHop as LdkRouteHintHop, RouteParameters,
+    ScorerAccountingForInFlightHtlcs,
+};
+use lampo_common::ldk::routing::scoring::ProbabilisticScoringFeeParameters;
+use lampo_common::ldk::util::config::UserConfig;
 use lampo_common::ldk::util::ser::Readable;
 use lampo_common::utils::logger::LampoLogger;
 use lightning_rapid_gossip_sync::RapidGossipSync;
 
+use crate::algorithms::probabilistic::scorer::{to_ldk_path, LdkGraph, ScorerStore};
+use crate::amount::Msat;
 use crate::graph::NetworkGraph;
-use crate::strategy::{RouteHop, RouteInput, RouteOutput, Strategy};
+use crate::local_channels::LocalChannel;
+use crate::node_id::NodeId;
+use crate::route_hints::RouteHint;
+use crate::short_channel_id::ShortChannelId;
+use crate::strategy::{
+    PaymentOutcome, RouteConstraints, RouteHop, RouteInput, RouteOutput, RoutePart, Strategy,
+};
+
+pub mod scorer;
 
 /// A routing strategy that uses the LDK crates to find the best route.
+///
+/// The probabilistic scorer is persisted under `root_path`, so what it learns
+/// from the payments is reused by the next ones.
 pub struct LDKRoutingStrategy {
     logger: Arc<LampoLogger>,
     network: Network,
-    root_path: String,
+    scorer: Arc<ScorerStore>,
 }
 
 impl LDKRoutingStrategy {
     pub fn new(network: Network, root_path: String) -> Self {
+        Self::with_scorer(network, Arc::new(ScorerStore::new(&root_path)))
+    }
+
+    /// Create the strategy with a scorer shared with other instances of it
+    pub fn with_scorer(network: Network, scorer: Arc<ScorerStore>) -> Self {
         Self {
             logger: Arc::new(LampoLogger::new()),
             network,
-            root_path,
+            scorer,
         }
     }
 
     fn convert_to_ldk_network_graph(
         &self,
         graph: &dyn NetworkGraph,
-    ) -> anyhow::Result<LdkNetworkGraph<Arc<LampoLogger>>> {
+        constraints: &RouteConstraints,
+    ) -> anyhow::Result<LdkGraph> {
         let ldkgraph = LdkNetworkGraph::new(self.network.clone(), self.logger.clone());
 
         for channel in graph.get_channels() {
+            // The excluded channels and nodes are left out of the LDK graph
+            if constraints
+                .excluded_channels
+                .contains(&channel.short_channel_id)
+                || constraints.excluded_nodes.contains(&channel.node1)
+                || constraints.excluded_nodes.contains(&channel.node2)
+            {
+                continue;
+            }
             // FIXME: we need to set the annouce message insie the channel struct
             if let Some(msg) = channel.channel_announcement.clone() {
                 let channel_ann = ChannelAnnouncement::read(&mut msg.as_slice())
@@ -51,51 +84,132 @@ impl LDKRoutingStrategy {
                     .update_channel_from_announcement_no_lookup(&channel_ann)
                     .map_err(|err| anyhow::anyhow!("{:?}", err))?;
             }
+
+            // LDK is not able to route over a channel without the directional
+            // updates, so we apply the ones we know. An update can be
+            // legitimately rejected (e.g. it is stale), and it is not worth
+            // failing the whole graph for it.
+            let policies = [channel.node1_policy.as_ref(), channel.node2_policy.as_ref()];
+            for msg in policies
+                .into_iter()
+                .flatten()
+                .filter_map(|policy| policy.channel_update.as_ref())
+            {
+                let channel_update =
+                    ChannelUpdate::read(&mut msg.as_slice()).map_err(|e| anyhow::anyhow!("{e}"))?;
+                if let Err(err) = ldkgraph.update_channel(&channel_update) {
+                    log::debug!(
+                        "Skipping channel update for `{}`: {:?}",
+                        channel.short_channel_id,
+                        err
+                    );
+                }
+            }
+        }
+
+        for node in graph.get_nodes() {
+            if let Some(msg) = node.node_announcement.as_ref() {
+                let node_ann = NodeAnnouncement::read(&mut msg.as_slice())
+                    .map_err(|e| anyhow::anyhow!("{e}"))?;
+                if let Err(err) = ldkgraph.update_node_from_announcement(&node_ann) {
+                    log::debug!("Skipping node announcement for `{}`: {:?}", node.id, err);
+                }
+            }
         }
 
         Ok(ldkgraph)
     }
 
-    fn construct_route_params(input: &RouteInput) -> RouteParameters {
-        // SAFETY: safe to unwrap because it should be a valid pub key
-        let payment_params = PaymentParameters::from_node_id(
-            PublicKey::from_str(&input.dest_pubkey).unwrap(),
-            input.cltv as u32,
+    fn construct_route_params(input: &RouteInput) -> Result<RouteParameters> {
+        let mut payment_params =
+            PaymentParameters::from_node_id(input.dest_pubkey.to_public_key(), input.cltv as u32);
+        // The LDK graph is built from the gossip, that does not know the
+        // private channels of the payee
+        if !input.route_hints.is_empty() {
+            let hints = input.route_hints.iter().map(to_ldk_route_hint).collect();
+            payment_params = payment_params
+                .with_route_hints(hints)
+                .map_err(|()| anyhow::anyhow!("Unable to route with the route hints"))?;
+        }
+        if let Some(max_cltv) = input.constraints.max_cltv {
+            payment_params.max_total_cltv_expiry_delta = max_cltv.try_into().unwrap_or(u32::MAX);
+        }
+        if let Some(max_parts) = input.constraints.max_parts {
+            payment_params.max_path_count = max_parts.try_into().unwrap_or(u8::MAX);
+        }
+        let mut route_params = RouteParameters::from_payment_params_and_value(
+            payment_params,
+            input.amount_msat.msat(),
         );
-        RouteParameters::from_payment_params_and_value(payment_params, input.amount_msat)
+        route_params.max_total_routing_fee_msat = input
+            .constraints
+            .max_fee_for(input.amount_msat)
+            .map(Msat::msat);
+        Ok(route_params)
     }
 
     fn convert_route_to_output(route: Route) -> RouteOutput {
-        let path = route.paths.first().expect("No LDK path available");
-        let mut amt_to_forward = 0;
+        let parts = route.paths.iter().map(Self::convert_path_to_part).collect();
+        RouteOutput { parts }
+    }
+
+    /// Convert a LDK path to the route expected by `sendpay`, as built by
+    /// CLN `getroute`
+    ///
+    /// A LDK hop carries the fee and the CLTV delta its node charges to
+    /// forward through the next channel, and the last one the amount and the
+    /// CLTV delivered to the destination. A CLN hop carries instead the
+    /// amount and the delay that reach its node, so we build them from the
+    /// destination backwards.
+    fn convert_path_to_part(path: &Path) -> RoutePart {
+        let final_value_msat = Msat::new(path.final_value_msat());
+        let mut hops = Vec::with_capacity(path.hops.len());
+        let mut amount_msat = final_value_msat;
         let mut delay = 0;
+        for (i, hop) in path.hops.iter().enumerate().rev() {
+            if i + 1 < path.hops.len() {
+                amount_msat += Msat::new(hop.fee_msat);
+            }
+            delay += hop.cltv_expiry_delta;
+            hops.push(RouteHop::new(
+                NodeId::from(hop.pubkey),
+                ShortChannelId::from(hop.short_channel_id),
+                delay,
+                amount_msat,
+            ));
+        }
+        hops.reverse();
+        RoutePart::new(final_value_msat, hops)
+    }
 
-        let output_path: Vec<RouteHop> = path
-            .hops
+    /// The channels we can start a route with, with the balance we can spend
+    /// on each
+    fn first_hops(input: &RouteInput) -> Vec<ChannelDetails> {
+        input
+            .local_channels
             .iter()
-            .rev()
-            .map(|hop| {
-                amt_to_forward += hop.fee_msat;
-                delay += hop.cltv_expiry_delta;
-
-                RouteHop::new(
-                    hop.pubkey.to_string(),
-                    hop.short_channel_id.to_string(),
-                    delay,
-                    amt_to_forward,
-                )
+            .filter(|channel| {
+                channel.is_usable()
+                    && input.allows_hop(
+                        &channel.short_channel_id,
+                        &input.src_pubkey,
+                        &channel.peer_id,
+                    )
             })
-            .collect();
+            .map(to_channel_details)
+            .collect()
+    }
 
-        RouteOutput {
-            path: output_path.into_iter().rev().collect(),
+    /// Our parts in flight, so the scorer knows the liquidity they use
+    fn inflight_htlcs(input: &RouteInput) -> LdkInFlightHtlcs {
+        let mut inflight = LdkInFlightHtlcs::new();
+        for (src_pubkey, part) in input.inflight.parts() {
+            inflight.process_path(&to_ldk_path(&part), src_pubkey.to_public_key());
         }
+        inflight
     }
 
-    fn rapid_gossip_sync_network(
-        &self,
-        network: Network,
-    ) -> Result<LdkNetworkGraph<Arc<LampoLogger>>> {
+    fn rapid_gossip_sync_network(&self, network: Network) -> Result<LdkGraph> {
         let graph = LdkNetworkGraph::new(network, self.logger.clone());
         let rapid_sync = RapidGossipSync::new(&graph, self.logger.clone());
 
@@ -142,47 +256,223 @@ impl Strategy for LDKRoutingStrategy {
     }
 
     fn route(&self, input: &RouteInput) -> Result<RouteOutput> {
-        let our_node_pubkey = PublicKey::from_str(&input.src_pubkey)
-            .map_err(|_| anyhow::anyhow!("Failed to parse source pubkey"))?;
-        let route_params = Self::construct_route_params(input);
+        let our_node_pubkey = input.src_pubkey.to_public_key();
+        let route_params = Self::construct_route_params(input)?;
 
-        let ldk_graph = if input.use_rapid_gossip_sync {
+        let ldk_graph = Arc::new(if input.use_rapid_gossip_sync {
             self.rapid_gossip_sync_network(input.network)?
         } else {
-            self.convert_to_ldk_network_graph(input.graph.as_ref())?
-        };
+            self.convert_to_ldk_network_graph(input.graph.as_ref(), &input.constraints)?
+        });
 
-        // FIXME: We should check if there is a better way for this.
-        let parms = ProbabilisticScoringDecayParameters::default();
+        // LDK only starts the routes with the first hops when given, so we
+        // give them only if we know our channels.
+        let first_hops = Self::first_hops(input);
+        let first_hops = first_hops.iter().collect::<Vec<_>>();
+        let first_hops = (!input.local_channels.is_empty()).then_some(first_hops.as_slice());
+
+        let inflight_htlcs = Self::inflight_htlcs(input);
         let feeparams = ProbabilisticScoringFeeParameters::default();
-        let scorer = ProbabilisticScorer::new(parms, &ldk_graph, self.logger.clone());
 
         // FIXME: Implement the logic to generate random seed bytes
         let random_seed_bytes = [0; 32];
 
-        let route = find_route(
-            &our_node_pubkey,
-            &route_params,
-            &ldk_graph,
-            None,
-            self.logger.deref(),
-            &scorer,
-            &feeparams,
-            &random_seed_bytes,
-        )
-        // FIXME: we are losing context, we should return an better error for the plugin
-        .map_err(|e| anyhow::anyhow!("Failed to find route: {:?}", e))?;
-
-        Ok(Self::convert_route_to_output(route))
+        let route = self
+            .scorer
+            .with_scorer(ldk_graph.clone(), |scorer| {
+                find_route(
+                    &our_node_pubkey,
+                    &route_params,
+                    &ldk_graph,
+                    first_hops,
+                    self.logger.deref(),
+                    &ScorerAccountingForInFlightHtlcs::new(scorer, &inflight_htlcs),
+                    &feeparams,
+                    &random_seed_bytes,
+                )
+            })
+            // FIXME: we are losing context, we should return an better error for the plugin
+            .map_err(|e| anyhow::anyhow!("Failed to find route: {:?}", e))?;
+
+        // LDK does not know about the other constraints (e.g. the number of
+        // hops), so we check them on the route it found.
+        let output = Self::convert_route_to_output(route);
+        input.check_constraints(&output)?;
+        Ok(output)
+    }
+
+    /// Teach the scorer the outcome of the part
+    fn on_payment_result(&self, part: &RoutePart, outcome: &PaymentOutcome) {
+        let result = match outcome {
+            PaymentOutcome::Success => self.scorer.payment_path_successful(part),
+            PaymentOutcome::Failure(failure) => match failure.erring_channel.as_ref() {
+                Some(channel) => self.scorer.payment_path_failed(part, channel),
+                // We do not know where the part failed
+                None => return,
+            },
+        };
+        if let Err(err) = result {
+            log::warn!("Unable to update the scorer: {err}");
+        }
+    }
+}
+
+/// Describe our channel the way LDK knows its own channels
+///
+/// LDK only reads the counterparty, the short channel id and the HTLC limits
+/// of the first hops, the other fields are left to their defaults.
+fn to_channel_details(channel: &LocalChannel) -> ChannelDetails {
+    let spendable_msat = channel.sendable_msat().msat();
+    ChannelDetails {
+        channel_id: ChannelId::from_bytes([0; 32]),
+        counterparty: ChannelCounterparty {
+            node_id: channel.peer_id.to_public_key(),
+            features: provided_init_features(&UserConfig::default()),
+            unspendable_punishment_reserve: 0,
+            forwarding_info: None,
+            outbound_htlc_minimum_msat: None,
+            outbound_htlc_maximum_msat: None,
+        },
+        funding_txo: None,
+        channel_type: None,
+        short_channel_id: Some(channel.short_channel_id.to_u64()),
+        outbound_scid_alias: None,
+        inbound_scid_alias: None,
+        channel_value_satoshis: channel.total_msat.to_sat_floor().sat(),
+        unspendable_punishment_reserve: None,
+        user_channel_id: 0,
+        feerate_sat_per_1000_weight: None,
+        balance_msat: spendable_msat,
+        outbound_capacity_msat: spendable_msat,
+        next_outbound_htlc_limit_msat: spendable_msat,
+        next_outbound_htlc_minimum_msat: channel.minimum_htlc_out_msat.msat(),
+        inbound_capacity_msat: channel.receivable_msat.msat(),
+        confirmations_required: None,
+        confirmations: None,
+        force_close_spend_delay: None,
+        is_outbound: true,
+        is_channel_ready: true,
+        channel_shutdown_state: None,
+        is_usable: true,
+        is_public: !channel.private,
+        inbound_htlc_minimum_msat: None,
+        inbound_htlc_maximum_msat: None,
+        config: None,
     }
 }
 
+/// Describe the route hint the way LDK reads it from a BOLT11 invoice
+fn to_ldk_route_hint(hint: &RouteHint) -> LdkRouteHint {
+    let hops = hint
+        .0
+        .iter()
+        .map(|hop| LdkRouteHintHop {
+            src_node_id: hop.pubkey.to_public_key(),
+            short_channel_id: hop.short_channel_id.to_u64(),
+            fees: RoutingFees {
+                base_msat: hop.fee_base_msat.msat().try_into().unwrap_or(u32::MAX),
+                proportional_millionths: hop
+                    .fee_proportional_millionths
+                    .try_into()
+                    .unwrap_or(u32::MAX),
+            },
+            cltv_expiry_delta: hop.cltv_expiry_delta.try_into().unwrap_or(u16::MAX),
+            htlc_minimum_msat: None,
+            htlc_maximum_msat: None,
+        })
+        .collect();
+    LdkRouteHint(hops)
+}
+
 #[cfg(test)]
 mod tests {
 
+    use std::str::FromStr;
+
     use super::*;
+    use lampo_common::bitcoin::secp256k1::PublicKey;
+    use lampo_common::ldk::ln::features::{ChannelFeatures, NodeFeatures};
+    use lampo_common::ldk::routing::router::RouteHop as LdkRouteHop;
     use lampo_common::ldk::util::logger::{Logger, Record};
 
+    const L2: &str = "022d223620a359a47ff7f7ac447c85c46c923da53389221a0054c11c1e3ca31d59";
+    const L3: &str = "035d2b1192dfba134e10e540875d366ebc8bc353d5aa766b80c090b39c3a5d885d";
+    const L4: &str = "0382ce59ebf18be7d84677c2e35f23294b9992ceca95491fcf8a56c6cb2d9de199";
+
+    /// Build a LDK path from `(node, short channel id, fee, cltv delta)`
+    fn ldk_path(hops: &[(&str, &str, u64, u32)]) -> Path {
+        let hops = hops
+            .iter()
+            .map(|(node, scid, fee_msat, cltv_expiry_delta)| LdkRouteHop {
+                pubkey: PublicKey::from_str(node).unwrap(),
+                node_features: NodeFeatures::empty(),
+                short_channel_id: scid.parse::<ShortChannelId>().unwrap().to_u64(),
+                channel_features: ChannelFeatures::empty(),
+                fee_msat: *fee_msat,
+                cltv_expiry_delta: *cltv_expiry_delta,
+                maybe_announced_channel: true,
+            })
+            .collect();
+        Path {
+            hops,
+            blinded_tail: None,
+        }
+    }
+
+    /// Check the converted path against the route of CLN `getroute`
+    fn assert_getroute(path: Path, getroute: &str) {
+        let route = Route {
+            paths: vec![path],
+            route_params: None,
+        };
+        let output = LDKRoutingStrategy::convert_route_to_output(route);
+        let expected: Vec<RouteHop> = serde_json::from_str(getroute).unwrap();
+
+        assert_eq!(output.parts.len(), 1);
+        assert_eq!(output.parts[0].path, expected);
+        assert_eq!(
+            output.parts[0].amount_msat,
+            expected.last().unwrap().amount_msat
+        );
+    }
+
+    #[test]
+    fn test_convert_two_hops() {
+        // l1 -> l2 -> l3 with the default fees of CLN (1 msat + 10 ppm, 6
+        // blocks), `getroute l3 1000000 1 9`
+        let path = ldk_path(&[(L2, "103x1x0", 11, 6), (L3, "105x1x0", 1_000_000, 9)]);
+        assert_getroute(
+            path,
+            &format!(
+                r#"[
+                    {{"id": "{L2}", "channel": "103x1x0", "direction": 1, "amount_msat": 1000011, "delay": 15, "style": "tlv"}},
+                    {{"id": "{L3}", "channel": "105x1x0", "direction": 0, "amount_msat": 1000000, "delay": 9, "style": "tlv"}}
+                ]"#
+            ),
+        );
+    }
+
+    #[test]
+    fn test_convert_three_hops() {
+        // l1 -> l2 -> l3 -> l4, where l2 charges 1000 msat + 100 ppm and 14
+        // blocks, and l3 1000 ppm and 40 blocks, `getroute l4 50000 1 18`
+        let path = ldk_path(&[
+            (L2, "103x1x0", 1_005, 14),
+            (L3, "105x1x0", 50, 40),
+            (L4, "107x1x0", 50_000, 18),
+        ]);
+        assert_getroute(
+            path,
+            &format!(
+                r#"[
+                    {{"id": "{L2}", "channel": "103x1x0", "direction": 1, "amount_msat": 51055, "delay": 72, "style": "tlv"}},
+                    {{"id": "{L3}", "channel": "105x1x0", "direction": 0, "amount_msat": 50050, "delay": 58, "style": "tlv"}},
+                    {{"id": "{L4}", "channel": "107x1x0", "direction": 1, "amount_msat": 50000, "delay": 18, "style": "tlv"}}
+                ]"#
+            ),
+        );
+    }
+
     #[test]
     fn test_rapid_gossip_sync_network_sanity() {
         let network = Network::Bitcoin;
@@ -0,0 +1,150 @@
+//! Persistence of the LDK `ProbabilisticScorer` across payments
+
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use lampo_common::ldk::ln::features::{ChannelFeatures, NodeFeatures};
+use lampo_common::ldk::routing::gossip::NetworkGraph as LdkNetworkGraph;
+use lampo_common::ldk::routing::router::{Path, RouteHop as LdkRouteHop};
+use lampo_common::ldk::routing::scoring::{
+    ProbabilisticScorer, ProbabilisticScoringDecayParameters, ScoreUpdate,
+};
+use lampo_common::ldk::util::ser::{ReadableArgs, Writeable};
+use lampo_common::utils::logger::LampoLogger;
+
+use crate::short_channel_id::ShortChannelId;
+use crate::strategy::RoutePart;
+
+/// Name of the file the scorer is persisted to, under the root path
+const SCORER_FILE: &str = "barq_scorer";
+
+pub type LdkGraph = LdkNetworkGraph<Arc<LampoLogger>>;
+pub type Scorer = ProbabilisticScorer<Arc<LdkGraph>, Arc<LampoLogger>>;
+
+/// What the scorer learned so far
+enum ScorerState {
+    /// The scorer as persisted on disk, waiting for a graph to score
+    Saved(Vec<u8>),
+    /// The scorer used by the last route, with the graph it scores
+    Loaded(Scorer),
+}
+
+/// A `ProbabilisticScorer` persisted under the root path of the strategy
+///
+/// The scorer only knows about the graph of the last route, but what it
+/// learned is carried over to the graphs of the next routes and is updated
+/// with the result of every payment.
+pub struct ScorerStore {
+    path: PathBuf,
+    logger: Arc<LampoLogger>,
+    state: Mutex<ScorerState>,
+}
+
+impl ScorerStore {
+    /// Load the scorer persisted under `root_path`, if any
+    pub fn new(root_path: &str) -> Self {
+        let path = FsPath::new(root_path).join(SCORER_FILE);
+        let saved = std::fs::read(&path).unwrap_or_else(|err| {
+            log::debug!("No scorer loaded from {:?}: {err}", path);
+            Vec::new()
+        });
+        ScorerStore {
+            path,
+            logger: Arc::new(LampoLogger::new()),
+            state: Mutex::new(ScorerState::Saved(saved)),
+        }
+    }
+
+    /// Run `f` with the scorer, moved to `graph`
+    pub fn with_scorer<R>(&self, graph: Arc<LdkGraph>, f: impl FnOnce(&Scorer) -> R) -> R {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let saved = match &*state {
+            ScorerState::Saved(saved) => saved.clone(),
+            ScorerState::Loaded(scorer) => scorer.encode(),
+        };
+        let decay = ProbabilisticScoringDecayParameters::default();
+        let scorer = if saved.is_empty() {
+            Scorer::new(decay, graph, self.logger.clone())
+        } else {
+            Scorer::read(
+                &mut saved.as_slice(),
+                (decay, graph.clone(), self.logger.clone()),
+            )
+            .unwrap_or_else(|err| {
+                log::warn!("Unable to read the persisted scorer: {err:?}");
+                Scorer::new(decay, graph, self.logger.clone())
+            })
+        };
+        let result = f(&scorer);
+        *state = ScorerState::Loaded(scorer);
+        result
+    }
+
+    /// Learn that `part` failed at the channel `short_channel_id`
+    pub fn payment_path_failed(
+        &self,
+        part: &RoutePart,
+        short_channel_id: &ShortChannelId,
+    ) -> Result<()> {
+        let path = to_ldk_path(part);
+        let short_channel_id = short_channel_id.to_u64();
+        self.update(|scorer, now| scorer.payment_path_failed(&path, short_channel_id, now))
+    }
+
+    /// Learn that `part` was delivered
+    pub fn payment_path_successful(&self, part: &RoutePart) -> Result<()> {
+        let path = to_ldk_path(part);
+        self.update(|scorer, now| scorer.payment_path_successful(&path, now))
+    }
+
+    /// Update the scorer, if it was loaded, and persist it
+    fn update(&self, f: impl FnOnce(&mut Scorer, Duration)) -> Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let ScorerState::Loaded(scorer) = &mut *state else {
+            // Nothing was routed with the scorer yet, so it does not know
+            // the channels of the path.
+            return Ok(());
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        scorer.time_passed(now);
+        f(scorer, now);
+
+        // Write a new file and rename it, so a crash never leaves a
+        // truncated scorer behind
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, scorer.encode())?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Build the LDK path of `part`, where each hop carries the fee and the CLTV
+/// delta paid to it and the last one the amount and the CLTV delivered
+pub(crate) fn to_ldk_path(part: &RoutePart) -> Path {
+    let mut hops = Vec::with_capacity(part.path.len());
+    for (i, hop) in part.path.iter().enumerate() {
+        let (fee_msat, cltv_expiry_delta) = match part.path.get(i + 1) {
+            Some(next) => (
+                hop.amount_msat.saturating_sub(next.amount_msat).msat(),
+                hop.delay.saturating_sub(next.delay),
+            ),
+            None => (hop.amount_msat.msat(), hop.delay),
+        };
+        hops.push(LdkRouteHop {
+            pubkey: hop.id.to_public_key(),
+            node_features: NodeFeatures::empty(),
+            short_channel_id: hop.channel.to_u64(),
+            channel_features: ChannelFeatures::empty(),
+            fee_msat,
+            cltv_expiry_delta,
+            maybe_announced_channel: true,
+        });
+    }
+    Path {
+        hops,
+        blinded_tail: None,
+    }
+}
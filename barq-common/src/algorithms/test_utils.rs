@@ -0,0 +1,166 @@
+//! Helpers to test the routing strategies on small handmade graphs
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use lampo_common::bitcoin::hashes::{sha256, Hash};
+use lampo_common::bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use lampo_common::conf::Network;
+
+use crate::amount::Msat;
+use crate::graph::{Channel, ChannelPolicy, NetworkGraph, Node};
+use crate::inflight::InFlightHtlcs;
+use crate::liquidity::LiquidityStore;
+use crate::local_channels::{LocalChannel, LocalChannels, NORMAL_STATE};
+use crate::node_id::NodeId;
+use crate::short_channel_id::ShortChannelId;
+use crate::strategy::{RouteConstraints, RouteInput};
+
+/// An in-memory network graph
+#[derive(Default)]
+pub struct TestGraph {
+    nodes: HashMap<NodeId, Node>,
+    channels: HashMap<ShortChannelId, Channel>,
+}
+
+impl TestGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a channel of `capacity` msat with the `(delay, base fee in msat,
+    /// fee rate)` policy of each direction.
+    pub fn add_channel(
+        &mut self,
+        id: &str,
+        node1: &str,
+        node2: &str,
+        capacity: u64,
+        policy1: (u64, u64, u64),
+        policy2: (u64, u64, u64),
+    ) {
+        let mut channel = Channel::new(scid(id), node(node1), node(node2), Msat::new(capacity));
+        channel.set_policy(
+            0,
+            ChannelPolicy::new(policy1.0, Msat::new(policy1.1), policy1.2),
+        );
+        channel.set_policy(
+            1,
+            ChannelPolicy::new(policy2.0, Msat::new(policy2.1), policy2.2),
+        );
+        self.insert(channel);
+    }
+
+    /// Disables the direction of the channel going out of `from`.
+    pub fn disable(&mut self, id: &str, from: &str) {
+        let mut channel = self.channels[&scid(id)].clone();
+        let from = node(from);
+        let direction = if channel.node1 == from { 0 } else { 1 };
+        let mut policy = channel.policy_from(&from).unwrap().clone();
+        policy.disabled = true;
+        channel.set_policy(direction, policy);
+        self.insert(channel);
+    }
+
+    fn insert(&mut self, channel: Channel) {
+        for node_id in [channel.node1, channel.node2] {
+            self.nodes
+                .entry(node_id)
+                .or_insert_with(|| Node::new(node_id))
+                .add_channel(&channel);
+        }
+        self.channels.insert(channel.short_channel_id, channel);
+    }
+}
+
+impl NetworkGraph for TestGraph {
+    fn get_channels(&self) -> Vec<&Channel> {
+        self.channels.values().collect()
+    }
+
+    fn get_nodes(&self) -> Vec<&Node> {
+        self.nodes.values().collect()
+    }
+
+    fn get_node(&self, id: &NodeId) -> Option<&Node> {
+        self.nodes.get(id)
+    }
+
+    fn get_channel(&self, id: &ShortChannelId) -> Option<&Channel> {
+        self.channels.get(id)
+    }
+
+    fn remove_channel(&mut self, id: &ShortChannelId) -> Option<Channel> {
+        let channel = self.channels.remove(id)?;
+        for node_id in [&channel.node1, &channel.node2] {
+            if let Some(node) = self.nodes.get_mut(node_id) {
+                node.remove_channel(id);
+            }
+        }
+        Some(channel)
+    }
+
+    fn remove_node(&mut self, id: &NodeId) -> Option<Node> {
+        let node = self.nodes.remove(id)?;
+        for channel in &node.channels {
+            self.remove_channel(&channel.short_channel_id);
+        }
+        Some(node)
+    }
+
+    fn has_p2p_info(&self) -> bool {
+        false
+    }
+}
+
+/// Parses a short channel id in the `103x1x0` notation
+pub fn scid(id: &str) -> ShortChannelId {
+    id.parse().unwrap()
+}
+
+/// The node id of the test node `name`, derived from its name
+pub fn node(name: &str) -> NodeId {
+    let secret = sha256::Hash::hash(name.as_bytes()).to_byte_array();
+    // SAFETY: a hash is a valid secret key with overwhelming probability.
+    let secret = SecretKey::from_slice(&secret).unwrap();
+    NodeId::from(PublicKey::from_secret_key(&Secp256k1::new(), &secret))
+}
+
+/// Our usable channel `id` with `peer`, where we can spend `spendable_msat`
+pub fn local_channel(id: &str, peer: &str, spendable_msat: u64) -> LocalChannel {
+    LocalChannel {
+        short_channel_id: scid(id),
+        peer_id: node(peer),
+        peer_connected: true,
+        state: NORMAL_STATE.to_string(),
+        total_msat: Msat::new(1_000_000),
+        spendable_msat: Msat::new(spendable_msat),
+        receivable_msat: Msat::ZERO,
+        minimum_htlc_out_msat: Msat::ZERO,
+        private: false,
+    }
+}
+
+/// Builds the input to route `amount_msat` from `src` to `dest` on `graph`
+pub fn route_input(
+    graph: TestGraph,
+    src: &str,
+    dest: &str,
+    amount_msat: u64,
+    cltv: u64,
+) -> RouteInput {
+    RouteInput {
+        src_pubkey: node(src),
+        dest_pubkey: node(dest),
+        network: Network::Regtest,
+        amount_msat: Msat::new(amount_msat),
+        cltv,
+        graph: Arc::new(graph),
+        use_rapid_gossip_sync: false,
+        constraints: RouteConstraints::default(),
+        liquidity: Arc::new(LiquidityStore::default()),
+        local_channels: Arc::new(LocalChannels::default()),
+        inflight: Arc::new(InFlightHtlcs::default()),
+        route_hints: vec![],
+    }
+}
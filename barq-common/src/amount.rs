@@ -0,0 +1,178 @@
+//! Amounts in millisatoshi and satoshi
+//!
+//! The gossip announces capacities in satoshi while payments are made in
+//! millisatoshi, so each unit has its own type and the conversions are
+//! explicit. The arithmetic never wraps: the operators panic on overflow,
+//! and the `checked_*` and `saturating_*` methods handle it explicitly.
+
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+
+use serde::{Deserialize, Serialize};
+
+/// An amount in millisatoshi
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Msat(u64);
+
+/// An amount in satoshi
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Sat(u64);
+
+impl Msat {
+    pub const ZERO: Msat = Msat(0);
+    pub const MAX: Msat = Msat(u64::MAX);
+
+    pub const fn new(msat: u64) -> Self {
+        Msat(msat)
+    }
+
+    /// The amount as a number of millisatoshi
+    pub const fn msat(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Msat) -> Option<Msat> {
+        self.0.checked_add(other.0).map(Msat)
+    }
+
+    pub fn checked_sub(self, other: Msat) -> Option<Msat> {
+        self.0.checked_sub(other.0).map(Msat)
+    }
+
+    pub fn checked_mul(self, factor: u64) -> Option<Msat> {
+        self.0.checked_mul(factor).map(Msat)
+    }
+
+    pub fn saturating_add(self, other: Msat) -> Msat {
+        Msat(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Msat) -> Msat {
+        Msat(self.0.saturating_sub(other.0))
+    }
+
+    /// `ppm` millionths of the amount, rounded down like the proportional
+    /// fee of a channel
+    pub fn ppm(self, ppm: u64) -> Msat {
+        let proportional = self.0 as u128 * ppm as u128 / 1_000_000;
+        Msat(proportional.try_into().unwrap_or(u64::MAX))
+    }
+
+    /// The amount in satoshi, rounded down
+    pub fn to_sat_floor(self) -> Sat {
+        Sat(self.0 / 1000)
+    }
+}
+
+impl Sat {
+    pub const ZERO: Sat = Sat(0);
+
+    pub const fn new(sat: u64) -> Self {
+        Sat(sat)
+    }
+
+    /// The amount as a number of satoshi
+    pub const fn sat(self) -> u64 {
+        self.0
+    }
+
+    /// The amount in millisatoshi, if it fits
+    pub fn to_msat(self) -> Option<Msat> {
+        self.0.checked_mul(1000).map(Msat)
+    }
+}
+
+impl From<Sat> for Msat {
+    /// Convert to millisatoshi, saturating for amounts way beyond the
+    /// bitcoin supply
+    fn from(value: Sat) -> Self {
+        value.to_msat().unwrap_or(Msat::MAX)
+    }
+}
+
+impl Add for Msat {
+    type Output = Msat;
+
+    fn add(self, other: Msat) -> Msat {
+        self.checked_add(other).expect("msat addition overflow")
+    }
+}
+
+impl AddAssign for Msat {
+    fn add_assign(&mut self, other: Msat) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Msat {
+    type Output = Msat;
+
+    fn sub(self, other: Msat) -> Msat {
+        self.checked_sub(other).expect("msat subtraction underflow")
+    }
+}
+
+impl SubAssign for Msat {
+    fn sub_assign(&mut self, other: Msat) {
+        *self = *self - other;
+    }
+}
+
+impl Sum for Msat {
+    fn sum<I: Iterator<Item = Msat>>(iter: I) -> Msat {
+        iter.fold(Msat::ZERO, Add::add)
+    }
+}
+
+impl<'a> Sum<&'a Msat> for Msat {
+    fn sum<I: Iterator<Item = &'a Msat>>(iter: I) -> Msat {
+        iter.copied().sum()
+    }
+}
+
+impl fmt::Display for Msat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}msat", self.0)
+    }
+}
+
+impl fmt::Display for Sat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}sat", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(Msat::from(Sat::new(21)), Msat::new(21_000));
+        assert_eq!(Msat::new(21_999).to_sat_floor(), Sat::new(21));
+        assert_eq!(Msat::new(1_000_000).ppm(10), Msat::new(10));
+        assert_eq!(Msat::new(1).checked_sub(Msat::new(2)), None);
+        assert_eq!(Msat::MAX.saturating_add(Msat::new(1)), Msat::MAX);
+        assert_eq!(
+            [Msat::new(1), Msat::new(2)].iter().sum::<Msat>(),
+            Msat::new(3)
+        );
+        assert_eq!(
+            json_roundtrip(Msat::new(1000)),
+            (String::from("1000"), Msat::new(1000))
+        );
+    }
+
+    fn json_roundtrip(amount: Msat) -> (String, Msat) {
+        let json = serde_json::to_string(&amount).unwrap();
+        let amount = serde_json::from_str(&json).unwrap();
+        (json, amount)
+    }
+}
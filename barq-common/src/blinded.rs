@@ -0,0 +1,219 @@
+//! Blinded paths of the BOLT12 invoices, and the onion paying through them
+//!
+//! A blinded path hides the last hops to the payee: we route the payment to
+//! its introduction node, and the onion carries the data the payee encrypted
+//! for each blinded hop, telling it how to forward the payment.
+//!
+//! See: https://github.com/lightning/bolts/blob/master/04-onion-routing.md#route-blinding
+
+use anyhow::Result;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::amount::Msat;
+use crate::node_id::NodeId;
+use crate::onion::{
+    forward_hops, to_hex, OnionHop, TlvStream, AMT_TO_FORWARD, OUTGOING_CLTV_VALUE,
+};
+use crate::strategy::RouteHop;
+
+/// BOLT4 `payload` TLV types of the blinded hops
+const ENCRYPTED_RECIPIENT_DATA: u64 = 10;
+const CURRENT_PATH_KEY: u64 = 12;
+const TOTAL_AMOUNT_MSAT: u64 = 18;
+
+/// What the blinded hops charge together to forward a payment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlindedPayInfo {
+    pub fee_base_msat: Msat,
+    pub fee_proportional_millionths: u64,
+    /// The CLTV delta of the blinded hops, including the one of the payee
+    pub cltv_expiry_delta: u64,
+}
+
+impl BlindedPayInfo {
+    /// The fee to deliver `amount_msat` to the payee, rounded up as BOLT4
+    /// requires
+    pub fn fee_msat(&self, amount_msat: Msat) -> Msat {
+        let proportional = (amount_msat.msat() as u128 * self.fee_proportional_millionths as u128)
+            .div_ceil(1_000_000);
+        self.fee_base_msat
+            .saturating_add(Msat::new(proportional.try_into().unwrap_or(u64::MAX)))
+    }
+
+    /// The largest amount delivered to the payee when `amount_msat` reaches
+    /// the introduction node
+    pub fn amount_delivered(&self, amount_msat: Msat) -> Msat {
+        let available = amount_msat.saturating_sub(self.fee_base_msat).msat() as u128;
+        let delivered =
+            available * 1_000_000 / (1_000_000 + self.fee_proportional_millionths as u128);
+        let mut delivered = Msat::new(delivered.try_into().unwrap_or(u64::MAX));
+        // The rounding of the fee may cost one more msat
+        while delivered > Msat::ZERO
+            && delivered.saturating_add(self.fee_msat(delivered)) > amount_msat
+        {
+            delivered -= Msat::new(1);
+        }
+        delivered
+    }
+}
+
+/// A hop of a blinded path, known to us only by its blinded node id
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlindedHop {
+    pub blinded_node_id: NodeId,
+    /// The forwarding instructions of the hop, that only it can read
+    #[serde(deserialize_with = "from_hex", serialize_with = "to_hex")]
+    pub encrypted_recipient_data: Vec<u8>,
+}
+
+/// A blinded path to the payee, as decoded by CLN `decode`
+///
+/// See: https://docs.corelightning.org/reference/lightning-decode#return-value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlindedPath {
+    /// The introduction node, unless the path starts with a short channel id
+    #[serde(default)]
+    pub first_node_id: Option<NodeId>,
+    /// The key the introduction node unblinds its data with, named `blinding`
+    /// by the older versions of CLN
+    #[serde(alias = "blinding")]
+    pub first_path_key: NodeId,
+    pub payinfo: BlindedPayInfo,
+    /// The blinded hops, starting with the introduction node
+    pub path: Vec<BlindedHop>,
+}
+
+/// The onion hops to deliver `amount_msat` of a `total_msat` payment through
+/// `blinded`, after the `route` reaching its introduction node
+///
+/// See `onion::forward_hops` for `base_expiry`.
+pub fn onion_hops(
+    route: &[RouteHop],
+    blinded: &BlindedPath,
+    amount_msat: Msat,
+    total_msat: Msat,
+    base_expiry: u64,
+) -> Result<Vec<OnionHop>> {
+    let Some(introduction) = route.last() else {
+        anyhow::bail!("The route to the blinded path is empty");
+    };
+    if blinded.first_node_id != Some(introduction.id) {
+        anyhow::bail!(
+            "The route ends at `{}`, not at the introduction node of the blinded path",
+            introduction.id
+        );
+    }
+    if blinded.path.is_empty() {
+        anyhow::bail!("The blinded path has no hop");
+    }
+    let last = blinded.path.len() - 1;
+
+    let mut hops = forward_hops(route, base_expiry);
+    for (i, blinded_hop) in blinded.path.iter().enumerate() {
+        let mut payload = TlvStream::default();
+        if i == last {
+            payload.truncated(AMT_TO_FORWARD, amount_msat.msat());
+            payload.truncated(OUTGOING_CLTV_VALUE, base_expiry);
+        }
+        payload.record(
+            ENCRYPTED_RECIPIENT_DATA,
+            &blinded_hop.encrypted_recipient_data,
+        );
+        if i == 0 {
+            payload.record(CURRENT_PATH_KEY, blinded.first_path_key.as_bytes());
+        }
+        if i == last {
+            payload.truncated(TOTAL_AMOUNT_MSAT, total_msat.msat());
+        }
+        hops.push(OnionHop {
+            // We reach the introduction node with its own id
+            pubkey: if i == 0 {
+                introduction.id
+            } else {
+                blinded_hop.blinded_node_id
+            },
+            payload: payload.into_payload(),
+        });
+    }
+    Ok(hops)
+}
+
+fn from_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    hex::decode(value).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::test_utils::{node, scid};
+
+    #[test]
+    fn test_blinded_fee() {
+        let payinfo = BlindedPayInfo {
+            fee_base_msat: Msat::new(1_000),
+            fee_proportional_millionths: 100,
+            cltv_expiry_delta: 144,
+        };
+        // 100 ppm of 123_456 msat is 12.3456 msat, rounded up
+        assert_eq!(payinfo.fee_msat(Msat::new(123_456)), Msat::new(1_013));
+        assert_eq!(
+            payinfo.amount_delivered(Msat::new(124_469)),
+            Msat::new(123_456)
+        );
+        assert_eq!(
+            payinfo.amount_delivered(Msat::new(124_468)),
+            Msat::new(123_455)
+        );
+        assert_eq!(payinfo.amount_delivered(Msat::new(999)), Msat::ZERO);
+    }
+
+    #[test]
+    fn test_onion_hops() {
+        let blinded = BlindedPath {
+            first_node_id: Some(node("carol")),
+            first_path_key: node("blinding"),
+            payinfo: BlindedPayInfo {
+                fee_base_msat: Msat::ZERO,
+                fee_proportional_millionths: 0,
+                cltv_expiry_delta: 100,
+            },
+            path: vec![
+                BlindedHop {
+                    blinded_node_id: node("blinded carol"),
+                    encrypted_recipient_data: vec![0xaa],
+                },
+                BlindedHop {
+                    blinded_node_id: node("blinded dave"),
+                    encrypted_recipient_data: vec![0xbb, 0xcc],
+                },
+            ],
+        };
+        let route = [
+            RouteHop::new(node("bob"), scid("1x2x3"), 150, Msat::new(1_001)),
+            RouteHop::new(node("carol"), scid("4x5x6"), 144, Msat::new(1_000)),
+        ];
+        let hops = onion_hops(&route, &blinded, Msat::new(900), Msat::new(2_000), 100).unwrap();
+
+        let payloads = hops
+            .iter()
+            .map(|hop| (hop.pubkey, hex::encode(&hop.payload)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            payloads,
+            vec![
+                // Forward 1000 msat through 4x5x6 with a CLTV of 244
+                (
+                    node("bob"),
+                    "11020203e80401f406080000040000050006".to_string()
+                ),
+                (node("carol"), format!("260a01aa0c21{}", node("blinding"))),
+                // Deliver 900 msat of 2000 at height 100
+                (
+                    node("blinded dave"),
+                    "0f020203840401640a02bbcc120207d0".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -1,22 +1,34 @@
-use clightningrpc_gossip_map::core::ToWire;
-use clightningrpc_gossip_map::gossip_types::GossipChannel;
 use serde::{Deserialize, Serialize};
 
+use crate::amount::Msat;
+use crate::node_id::NodeId;
+use crate::short_channel_id::ShortChannelId;
+
+/// `channel_flags` bit of a `channel_update` telling which end of the channel
+/// is announcing the policy
+pub const CHANNEL_FLAG_DIRECTION: u8 = 0x01;
+/// `channel_flags` bit of a `channel_update` telling that the direction is
+/// disabled
+pub const CHANNEL_FLAG_DISABLED: u8 = 0x02;
+
 /// Represents a node in the network graph.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Node {
-    pub id: String,
+    pub id: NodeId,
     pub alias: Option<String>,
     pub channels: Vec<Channel>,
+    /// The raw `node_announcement` gossip message, if known
+    pub node_announcement: Option<Vec<u8>>,
 }
 
 impl Node {
     /// Creates a new node.
-    pub fn new(id: &str) -> Self {
+    pub fn new(id: NodeId) -> Self {
         Node {
-            id: id.to_string(),
+            id,
             alias: None,
             channels: vec![],
+            node_announcement: None,
         }
     }
 
@@ -25,78 +37,169 @@ impl Node {
         self.alias = Some(alias.to_string());
     }
 
-    /// Adds a channel to the node.
+    /// Adds a channel to the node, replacing any previous version of the same
+    /// channel.
     pub fn add_channel(&mut self, channel: &Channel) {
+        self.remove_channel(&channel.short_channel_id);
         self.channels.push(channel.clone());
     }
+
+    /// Removes a channel from the node.
+    pub fn remove_channel(&mut self, id: &ShortChannelId) {
+        self.channels
+            .retain(|channel| channel.short_channel_id != *id);
+    }
 }
 
-/// Represents a channel between two nodes in the network graph.
+/// Routing policy of one direction of a channel, as announced by the node
+/// forwarding in that direction through a `channel_update`.
+///
+/// See: https://github.com/lightning/bolts/blob/master/07-routing-gossip.md#the-channel_update-message
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
-pub struct Channel {
-    pub short_channel_id: String,
-    pub node1: String,
-    pub node2: String,
-    pub capacity: u64,
+pub struct ChannelPolicy {
+    /// The CLTV delta the forwarding node requires
     pub delay: u64,
-    pub base_fee_millisatoshi: u64,
+    pub base_fee_millisatoshi: Msat,
     pub fee_per_millionth: u64,
-    // FIXME: add the p2p message from in here
+    pub htlc_minimum_msat: Msat,
+    pub htlc_maximum_msat: Option<Msat>,
+    /// Whether the forwarding node disabled this direction of the channel
+    pub disabled: bool,
+    /// Timestamp of the `channel_update` this policy comes from
+    pub last_update: u64,
+    /// The raw `channel_update` gossip message, if known
+    pub channel_update: Option<Vec<u8>>,
+}
+
+impl ChannelPolicy {
+    /// Creates a new enabled policy without HTLC limits
+    pub fn new(delay: u64, base_fee_millisatoshi: Msat, fee_per_millionth: u64) -> Self {
+        ChannelPolicy {
+            delay,
+            base_fee_millisatoshi,
+            fee_per_millionth,
+            htlc_minimum_msat: Msat::ZERO,
+            htlc_maximum_msat: None,
+            disabled: false,
+            last_update: 0,
+            channel_update: None,
+        }
+    }
+
+    /// Fee charged by the forwarding node to forward `amount_msat` through
+    /// the channel.
+    pub fn fee_msat(&self, amount_msat: Msat) -> Msat {
+        self.base_fee_millisatoshi
+            .saturating_add(amount_msat.ppm(self.fee_per_millionth))
+    }
+
+    /// Whether an HTLC of `amount_msat` can be forwarded with this policy.
+    pub fn can_forward(&self, amount_msat: Msat) -> bool {
+        !self.disabled
+            && amount_msat >= self.htlc_minimum_msat
+            && self
+                .htlc_maximum_msat
+                .is_none_or(|maximum| amount_msat <= maximum)
+    }
+}
+
+/// Represents a channel between two nodes in the network graph.
+///
+/// Following BOLT 7, `node1` is the node with the lexicographically lesser
+/// id, and each direction of the channel has its own policy.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Channel {
+    pub short_channel_id: ShortChannelId,
+    pub node1: NodeId,
+    pub node2: NodeId,
+    pub capacity: Msat,
+    /// Policy to forward from `node1` to `node2` (direction 0)
+    pub node1_policy: Option<ChannelPolicy>,
+    /// Policy to forward from `node2` to `node1` (direction 1)
+    pub node2_policy: Option<ChannelPolicy>,
+    /// The raw `channel_announcement` gossip message, if known
     pub channel_announcement: Option<Vec<u8>>,
-    // FIXME probably also the node one and node 2 announcement.
 }
 
 impl Channel {
-    /// Creates a new channel
-    pub fn new(
-        id: &str,
-        node1: &str,
-        node2: &str,
-        capacity: u64,
-        delay: u64,
-        base_fee_millisatoshi: u64,
-        fee_per_millionth: u64,
-    ) -> Self {
+    /// Creates a new channel without any policy
+    pub fn new(id: ShortChannelId, node1: NodeId, node2: NodeId, capacity: Msat) -> Self {
         Channel {
-            short_channel_id: id.to_string(),
-            node1: node1.to_string(),
-            node2: node2.to_string(),
+            short_channel_id: id,
+            node1,
+            node2,
             capacity,
-            delay,
-            base_fee_millisatoshi,
-            fee_per_millionth,
+            node1_policy: None,
+            node2_policy: None,
             channel_announcement: None,
         }
     }
 
     /// Sets the capacity of the channel.
-    pub fn set_capacity(&mut self, capacity: u64) {
+    pub fn set_capacity(&mut self, capacity: Msat) {
         self.capacity = capacity;
     }
-}
 
-impl From<GossipChannel> for Channel {
-    fn from(value: GossipChannel) -> Self {
-        // FIXME: we should encode the channel id
-        let mut val = Self::new(
-            &hex::encode(value.inner.short_channel_id),
-            &hex::encode(value.inner.node_id_1),
-            &hex::encode(value.inner.node_id_2),
-            value.satoshi.unwrap(),
-            0,
-            0,
-            0,
-        );
-        let mut buffer = Vec::new();
-        value.inner.to_wire(&mut buffer).unwrap();
-        val.channel_announcement = Some(buffer);
-        val
+    /// Sets the policy of the given direction (0 to forward from `node1`, 1
+    /// to forward from `node2`), unless we already know a newer one.
+    pub fn set_policy(&mut self, direction: u8, policy: ChannelPolicy) {
+        let current = if direction == 0 {
+            &mut self.node1_policy
+        } else {
+            &mut self.node2_policy
+        };
+        if current
+            .as_ref()
+            .is_none_or(|current| current.last_update <= policy.last_update)
+        {
+            *current = Some(policy);
+        }
+    }
+
+    /// Merges the policies known by `other` for the same channel into this
+    /// one.
+    pub fn merge(&mut self, other: &Channel) {
+        if let Some(policy) = other.node1_policy.clone() {
+            self.set_policy(0, policy);
+        }
+        if let Some(policy) = other.node2_policy.clone() {
+            self.set_policy(1, policy);
+        }
+        if self.channel_announcement.is_none() {
+            self.channel_announcement = other.channel_announcement.clone();
+        }
+    }
+
+    /// Gets the policy to forward through the channel from `node_id` to the
+    /// other end.
+    pub fn policy_from(&self, node_id: &NodeId) -> Option<&ChannelPolicy> {
+        if self.node1 == *node_id {
+            self.node1_policy.as_ref()
+        } else if self.node2 == *node_id {
+            self.node2_policy.as_ref()
+        } else {
+            None
+        }
+    }
+
+    /// Gets the other end of the channel, if `node_id` is one of its ends.
+    pub fn counterparty(&self, node_id: &NodeId) -> Option<NodeId> {
+        if self.node1 == *node_id {
+            Some(self.node2)
+        } else if self.node2 == *node_id {
+            Some(self.node1)
+        } else {
+            None
+        }
     }
 }
 
 /// Trait for handling network graphs with channels, nodes, and peer-to-peer
 /// information.
-pub trait NetworkGraph {
+///
+/// Graphs are shared between threads, e.g. the plugin caches them and
+/// refreshes them in the background.
+pub trait NetworkGraph: Send + Sync {
     /// Gets all channels in the network graph.
     fn get_channels(&self) -> Vec<&Channel>;
 
@@ -104,12 +207,76 @@ pub trait NetworkGraph {
     fn get_nodes(&self) -> Vec<&Node>;
 
     /// Gets a node by its ID.
-    fn get_node(&self, id: &str) -> Option<&Node>;
+    fn get_node(&self, id: &NodeId) -> Option<&Node>;
 
     /// Gets a channel by its ID.
-    fn get_channel(&self, id: &str) -> Option<&Channel>;
+    fn get_channel(&self, id: &ShortChannelId) -> Option<&Channel>;
+
+    /// Removes a channel from the network graph, returning it if it was
+    /// present.
+    fn remove_channel(&mut self, id: &ShortChannelId) -> Option<Channel>;
+
+    /// Removes a node and all of its channels from the network graph,
+    /// returning it if it was present.
+    fn remove_node(&mut self, id: &NodeId) -> Option<Node>;
 
     /// Whether or not the network graph has peer-to-peer information (e.g.,
     ///  gossip map).
     fn has_p2p_info(&self) -> bool;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::test_utils::{node, scid};
+
+    #[test]
+    fn test_merge_keeps_both_directions() {
+        let mut channel = Channel::new(
+            scid("103x1x0"),
+            node("alice"),
+            node("bob"),
+            Msat::new(1_000_000),
+        );
+        channel.set_policy(0, ChannelPolicy::new(6, Msat::new(1000), 10));
+
+        let mut other = Channel::new(
+            scid("103x1x0"),
+            node("alice"),
+            node("bob"),
+            Msat::new(1_000_000),
+        );
+        other.set_policy(1, ChannelPolicy::new(40, Msat::ZERO, 1));
+        channel.merge(&other);
+
+        assert_eq!(channel.policy_from(&node("alice")).unwrap().delay, 6);
+        assert_eq!(channel.policy_from(&node("bob")).unwrap().delay, 40);
+        assert_eq!(channel.counterparty(&node("bob")), Some(node("alice")));
+        assert!(channel.policy_from(&node("carol")).is_none());
+    }
+
+    #[test]
+    fn test_older_policy_is_ignored() {
+        let mut channel = Channel::new(
+            scid("103x1x0"),
+            node("alice"),
+            node("bob"),
+            Msat::new(1_000_000),
+        );
+        let mut newer = ChannelPolicy::new(6, Msat::new(1000), 10);
+        newer.last_update = 20;
+        let mut older = ChannelPolicy::new(144, Msat::ZERO, 0);
+        older.last_update = 10;
+
+        channel.set_policy(0, newer.clone());
+        channel.set_policy(0, older);
+        assert_eq!(channel.node1_policy, Some(newer));
+    }
+
+    #[test]
+    fn test_policy_fee() {
+        let policy = ChannelPolicy::new(6, Msat::new(1000), 10);
+        assert_eq!(policy.fee_msat(Msat::new(1_000_000)), Msat::new(1010));
+        assert_eq!(policy.fee_msat(Msat::new(99_999)), Msat::new(1000));
+    }
+}
@@ -0,0 +1,209 @@
+//! The parts we sent and that are not resolved yet
+//!
+//! The HTLCs of a part lock its amount on every channel of its path until it
+//! is resolved, so this liquidity is not available to the other parts, of the
+//! same payment or of a concurrent one.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::amount::Msat;
+use crate::node_id::NodeId;
+use crate::short_channel_id::ShortChannelId;
+use crate::strategy::RoutePart;
+
+/// Identifies a part the way CLN `sendpay` does
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PartId {
+    pub payment_hash: String,
+    pub groupid: u64,
+    pub partid: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// The parts with the generation they were added in and the node that
+    /// sent them
+    parts: HashMap<PartId, (u64, NodeId, RoutePart)>,
+    /// The number of parts added so far
+    generation: u64,
+    /// The amount locked on each channel, keyed by the channel and the node
+    /// forwarding through it
+    used: HashMap<(ShortChannelId, NodeId), Msat>,
+}
+
+/// The parts in flight, shared by all the payments
+#[derive(Default)]
+pub struct InFlightHtlcs {
+    inner: Mutex<Inner>,
+}
+
+impl InFlightHtlcs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track `part`, just sent by `src_pubkey`
+    pub fn add(&self, id: PartId, src_pubkey: NodeId, part: RoutePart) {
+        let mut inner = self.lock();
+        for (key, amount_msat) in hops(&src_pubkey, &part) {
+            let used = inner.used.entry(key).or_default();
+            *used = used.saturating_add(amount_msat);
+        }
+        let generation = inner.generation;
+        inner.generation += 1;
+        if let Some((_, src_pubkey, part)) = inner.parts.insert(id, (generation, src_pubkey, part))
+        {
+            Self::release(&mut inner, &src_pubkey, &part);
+        }
+    }
+
+    /// Stop tracking a part once it is resolved
+    pub fn remove(&self, id: &PartId) -> Option<RoutePart> {
+        let mut inner = self.lock();
+        let (_, src_pubkey, part) = inner.parts.remove(id)?;
+        Self::release(&mut inner, &src_pubkey, &part);
+        Some(part)
+    }
+
+    /// The current generation, to tell apart the parts added after it
+    pub fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    /// Stop tracking the parts added before `generation` that are not pending
+    /// anymore, e.g. the ones of a payment that was interrupted
+    ///
+    /// The parts added since `generation` are kept, as they may have been
+    /// sent after the pending parts were listed.
+    pub fn retain(&self, generation: u64, is_pending: impl Fn(&PartId) -> bool) {
+        let mut inner = self.lock();
+        let resolved = inner
+            .parts
+            .iter()
+            .filter(|(id, (added, _, _))| *added < generation && !is_pending(id))
+            .map(|(id, _)| id)
+            .cloned()
+            .collect::<Vec<_>>();
+        for id in resolved {
+            // SAFETY: we just listed the key.
+            let (_, src_pubkey, part) = inner.parts.remove(&id).unwrap();
+            Self::release(&mut inner, &src_pubkey, &part);
+        }
+    }
+
+    /// The amount locked by our parts on the channel, forwarded by `from`
+    pub fn used_msat(&self, short_channel_id: &ShortChannelId, from: &NodeId) -> Msat {
+        self.lock()
+            .used
+            .get(&(*short_channel_id, *from))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().parts.is_empty()
+    }
+
+    /// The parts in flight, with the node that sent them
+    pub fn parts(&self) -> Vec<(NodeId, RoutePart)> {
+        self.lock()
+            .parts
+            .values()
+            .map(|(_, src_pubkey, part)| (*src_pubkey, part.clone()))
+            .collect()
+    }
+
+    fn release(inner: &mut Inner, src_pubkey: &NodeId, part: &RoutePart) {
+        for (key, amount_msat) in hops(src_pubkey, part) {
+            if let Some(used) = inner.used.get_mut(&key) {
+                *used = used.saturating_sub(amount_msat);
+                if *used == Msat::ZERO {
+                    inner.used.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// The channels of `part` with the node forwarding through each, and the
+/// amount it forwards
+fn hops(src_pubkey: &NodeId, part: &RoutePart) -> Vec<((ShortChannelId, NodeId), Msat)> {
+    let mut from = *src_pubkey;
+    part.path
+        .iter()
+        .map(|hop| {
+            let key = (hop.channel, from);
+            from = hop.id;
+            (key, hop.amount_msat)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::test_utils::{node, scid};
+    use crate::strategy::RouteHop;
+
+    fn part_id(partid: u64) -> PartId {
+        PartId {
+            payment_hash: "00".repeat(32),
+            groupid: 0,
+            partid,
+        }
+    }
+
+    #[test]
+    fn test_used_liquidity() {
+        let inflight = InFlightHtlcs::new();
+        let part = RoutePart::new(
+            Msat::new(1_000),
+            vec![
+                RouteHop::new(node("bob"), scid("1x1x0"), 24, Msat::new(1_010)),
+                RouteHop::new(node("carol"), scid("2x1x0"), 18, Msat::new(1_000)),
+            ],
+        );
+        inflight.add(part_id(1), node("alice"), part.clone());
+        inflight.add(part_id(2), node("alice"), part.clone());
+
+        assert_eq!(
+            inflight.used_msat(&scid("1x1x0"), &node("alice")),
+            Msat::new(2_020)
+        );
+        assert_eq!(
+            inflight.used_msat(&scid("2x1x0"), &node("bob")),
+            Msat::new(2_000)
+        );
+        // The other direction is free
+        assert_eq!(
+            inflight.used_msat(&scid("2x1x0"), &node("carol")),
+            Msat::ZERO
+        );
+
+        inflight.remove(&part_id(1));
+        assert_eq!(
+            inflight.used_msat(&scid("2x1x0"), &node("bob")),
+            Msat::new(1_000)
+        );
+        // The parts added after the generation are kept
+        let generation = inflight.generation();
+        inflight.add(part_id(3), node("alice"), part);
+        inflight.retain(generation, |_| false);
+        assert_eq!(inflight.parts().len(), 1);
+        assert_eq!(
+            inflight.used_msat(&scid("1x1x0"), &node("alice")),
+            Msat::new(1_010)
+        );
+        inflight.retain(inflight.generation(), |_| false);
+        assert!(inflight.parts().is_empty());
+        assert_eq!(
+            inflight.used_msat(&scid("1x1x0"), &node("alice")),
+            Msat::ZERO
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Keysend, the spontaneous payments to a node without an invoice
+//!
+//! The payer picks the preimage and hands it to the payee in the onion, with
+//! any custom record it wants to attach to the payment.
+//!
+//! See: https://github.com/lightning/blips/blob/master/blip-0003.md
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use lampo_common::bitcoin::hashes::{sha256, Hash};
+
+use crate::onion::{forward_hops, OnionHop, TlvStream, AMT_TO_FORWARD, OUTGOING_CLTV_VALUE};
+use crate::strategy::RouteHop;
+
+/// The TLV type of the preimage in the payload of the payee
+pub const KEYSEND_TLV_TYPE: u64 = 5482373484;
+/// The CLTV delta the payee gets, it has no invoice to ask for one
+pub const KEYSEND_FINAL_CLTV: u64 = 22;
+/// The smallest TLV type of the custom records, the ones below are reserved
+/// to the protocol
+const MIN_CUSTOM_TLV_TYPE: u64 = 1 << 16;
+
+/// A keysend payment: the preimage we picked and the custom records to send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keysend {
+    pub preimage: [u8; 32],
+    pub extra_tlvs: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Keysend {
+    /// A keysend with a random preimage, and the hex encoded `extra_tlvs`
+    pub fn new(extra_tlvs: &BTreeMap<u64, String>) -> Result<Self> {
+        let extra_tlvs = extra_tlvs
+            .iter()
+            .map(|(kind, value)| {
+                if *kind < MIN_CUSTOM_TLV_TYPE || *kind == KEYSEND_TLV_TYPE {
+                    anyhow::bail!("TLV type `{kind}` is not a custom record type");
+                }
+                let value = hex::decode(value)
+                    .map_err(|err| anyhow::anyhow!("Invalid value of TLV `{kind}`: {err}"))?;
+                Ok((*kind, value))
+            })
+            .collect::<Result<_>>()?;
+        let mut preimage = [0; 32];
+        getrandom::getrandom(&mut preimage)
+            .map_err(|err| anyhow::anyhow!("Unable to generate the preimage: {err}"))?;
+        Ok(Keysend {
+            preimage,
+            extra_tlvs,
+        })
+    }
+
+    /// The hex encoded preimage
+    pub fn payment_preimage(&self) -> String {
+        hex::encode(self.preimage)
+    }
+
+    /// The hex encoded hash of the preimage
+    pub fn payment_hash(&self) -> String {
+        sha256::Hash::hash(&self.preimage).to_string()
+    }
+
+    /// The onion hops to pay the last node of `route` with keysend
+    ///
+    /// See `onion::forward_hops` for `base_expiry`.
+    pub fn onion_hops(&self, route: &[RouteHop], base_expiry: u64) -> Result<Vec<OnionHop>> {
+        let Some(payee) = route.last() else {
+            anyhow::bail!("The route to the payee is empty");
+        };
+        let mut hops = forward_hops(route, base_expiry);
+
+        let mut payload = TlvStream::default();
+        payload.truncated(AMT_TO_FORWARD, payee.amount_msat.msat());
+        payload.truncated(OUTGOING_CLTV_VALUE, base_expiry + payee.delay as u64);
+        // The records must be written in ascending order of type
+        let mut records = self
+            .extra_tlvs
+            .iter()
+            .map(|(kind, value)| (*kind, value.as_slice()))
+            .collect::<BTreeMap<_, _>>();
+        records.insert(KEYSEND_TLV_TYPE, &self.preimage);
+        for (kind, value) in records {
+            payload.record(kind, value);
+        }
+        hops.push(OnionHop {
+            pubkey: payee.id,
+            payload: payload.into_payload(),
+        });
+        Ok(hops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::test_utils::{node, scid};
+    use crate::amount::Msat;
+
+    #[test]
+    fn test_keysend_onion() {
+        let keysend = Keysend::new(&BTreeMap::from([(7629169, "cafe".to_string())])).unwrap();
+        assert_eq!(keysend.extra_tlvs[&7629169], vec![0xca, 0xfe]);
+        assert!(Keysend::new(&BTreeMap::from([(8, "00".to_string())])).is_err());
+
+        let keysend = Keysend {
+            preimage: [0x11; 32],
+            ..keysend
+        };
+        let route = [
+            RouteHop::new(node("bob"), scid("1x2x3"), 150, Msat::new(1_001)),
+            RouteHop::new(node("carol"), scid("4x5x6"), 22, Msat::new(1_000)),
+        ];
+        let hops = keysend.onion_hops(&route, 100).unwrap();
+        assert_eq!(hops.len(), 2);
+        assert_eq!(hops[1].pubkey, node("carol"));
+        // The custom record comes before the preimage, sorted by type
+        assert_eq!(
+            hex::encode(&hops[1].payload),
+            format!(
+                "39020203e804017afe0074697102cafeff0000000146c6616c20{}",
+                "11".repeat(32)
+            )
+        );
+    }
+}
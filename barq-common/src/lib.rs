@@ -1,5 +1,17 @@
 pub mod algorithms;
+pub mod amount;
+pub mod blinded;
 pub mod graph;
+pub mod inflight;
+pub mod keysend;
+pub mod liquidity;
+pub mod local_channels;
+pub mod node_id;
+pub mod onion;
+pub mod registry;
+pub mod route_hints;
+pub mod short_channel_id;
 pub mod strategy;
+pub mod validation;
 
 pub use lampo_common::conf::Network;
@@ -0,0 +1,367 @@
+//! Knowledge about the liquidity of the channels, shared by the strategies
+//!
+//! For each direction of a channel we keep a lower and an upper bound on the
+//! liquidity available to forward payments. The payments and the probes
+//! tighten the bounds, and the bounds decay back toward the whole capacity
+//! of the channel as time passes, since the liquidity keeps moving: every
+//! half-life the lower bound is halved and the upper bound doubled.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Msat;
+use crate::node_id::NodeId;
+use crate::short_channel_id::ShortChannelId;
+use crate::strategy::{PaymentOutcome, RoutePart};
+
+/// Name of the file the knowledge is persisted to, under the root path
+const LIQUIDITY_FILE: &str = "barq_liquidity.json";
+
+/// Default time after which half of what we learned about a channel is
+/// forgotten, same as the LDK scorer
+pub const DEFAULT_HALF_LIFE: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Number of half-lives after which the bounds are forgotten altogether
+const FORGET_AFTER_HALF_LIVES: u64 = 16;
+
+/// BOLT4 `temporary_channel_failure`, returned by a node that could not
+/// forward the amount on the channel
+///
+/// See: https://github.com/lightning/bolts/blob/master/04-onion-routing.md#failure-messages
+const TEMPORARY_CHANNEL_FAILURE: u16 = 0x1007;
+
+/// What we know about the liquidity of a direction of a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Bounds {
+    /// The channel was able to forward this amount
+    min_msat: Msat,
+    /// The channel was not able to forward more than this amount
+    max_msat: Option<Msat>,
+    /// When the bounds were last updated, in seconds since the UNIX epoch
+    updated_at: u64,
+}
+
+/// The liquidity bounds of the channels, keyed by directed channel
+///
+/// A directed channel is identified like in CLN, as `<scid>/<direction>`
+/// where the direction is 0 when forwarding from the node with the lowest id.
+pub struct LiquidityStore {
+    path: Option<PathBuf>,
+    half_life: Duration,
+    bounds: Mutex<HashMap<String, Bounds>>,
+}
+
+impl LiquidityStore {
+    /// An empty in-memory store
+    pub fn new(half_life: Duration) -> Self {
+        LiquidityStore {
+            path: None,
+            half_life,
+            bounds: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Load the store persisted under `root_path`, if any
+    pub fn load(root_path: &str, half_life: Duration) -> Self {
+        let path = Path::new(root_path).join(LIQUIDITY_FILE);
+        let bounds = std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(serde_json::from_slice(&content)?))
+            .unwrap_or_else(|err| {
+                log::debug!("No liquidity bounds loaded from {:?}: {err}", path);
+                HashMap::new()
+            });
+        LiquidityStore {
+            path: Some(path),
+            half_life,
+            bounds: Mutex::new(bounds),
+        }
+    }
+
+    /// Persist the store, forgetting the bounds that decayed away
+    pub fn persist(&self) -> Result<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        let now = now();
+        let mut bounds = self.bounds.lock().unwrap_or_else(|err| err.into_inner());
+        let forget_after = self.half_life.as_secs() * FORGET_AFTER_HALF_LIVES;
+        bounds.retain(|_, bounds| now.saturating_sub(bounds.updated_at) < forget_after);
+
+        // Write a new file and rename it, so a crash never leaves a
+        // truncated file behind
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&*bounds)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// The `(min, max)` liquidity available to forward from `from` to `to`
+    /// on a channel of `capacity_msat`
+    ///
+    /// Without any knowledge, the liquidity is anywhere in `[0, capacity]`.
+    pub fn bounds(
+        &self,
+        short_channel_id: &ShortChannelId,
+        from: &NodeId,
+        to: &NodeId,
+        capacity_msat: Msat,
+    ) -> (Msat, Msat) {
+        self.bounds_at(short_channel_id, from, to, capacity_msat, now())
+    }
+
+    /// Learn that the channel forwarded `amount_msat` from `from` to `to`
+    pub fn record_success(
+        &self,
+        short_channel_id: &ShortChannelId,
+        from: &NodeId,
+        to: &NodeId,
+        amount_msat: Msat,
+    ) {
+        self.update_at(short_channel_id, from, to, now(), |bounds| {
+            bounds.min_msat = bounds.min_msat.max(amount_msat);
+            if bounds.max_msat.is_some_and(|max| max < amount_msat) {
+                bounds.max_msat = Some(amount_msat);
+            }
+        });
+    }
+
+    /// Learn that the channel could not forward `amount_msat` from `from` to
+    /// `to`
+    pub fn record_failure(
+        &self,
+        short_channel_id: &ShortChannelId,
+        from: &NodeId,
+        to: &NodeId,
+        amount_msat: Msat,
+    ) {
+        let max = amount_msat.saturating_sub(Msat::new(1));
+        self.update_at(short_channel_id, from, to, now(), |bounds| {
+            bounds.max_msat = Some(bounds.max_msat.map_or(max, |known| known.min(max)));
+            bounds.min_msat = bounds.min_msat.min(max);
+        });
+    }
+
+    /// Learn from the outcome of a part of a payment, or of a probe, sent
+    /// from `src_pubkey`
+    ///
+    /// The channels before the erring node forwarded the part, and the
+    /// channel after it did not have enough liquidity when it failed with
+    /// `temporary_channel_failure`.
+    pub fn record_payment(&self, src_pubkey: &NodeId, part: &RoutePart, outcome: &PaymentOutcome) {
+        let (forwarded, failed) = match outcome {
+            PaymentOutcome::Success => (part.path.len(), false),
+            PaymentOutcome::Failure(failure) => {
+                let Some(index) = failure.erring_index else {
+                    // We do not know where the part failed
+                    return;
+                };
+                let index = (index as usize).min(part.path.len());
+                (index, failure.failcode == Some(TEMPORARY_CHANNEL_FAILURE))
+            }
+        };
+        let mut from = src_pubkey;
+        for (i, hop) in part.path.iter().enumerate() {
+            if i < forwarded {
+                self.record_success(&hop.channel, from, &hop.id, hop.amount_msat);
+            } else {
+                if failed {
+                    self.record_failure(&hop.channel, from, &hop.id, hop.amount_msat);
+                }
+                break;
+            }
+            from = &hop.id;
+        }
+    }
+
+    fn bounds_at(
+        &self,
+        short_channel_id: &ShortChannelId,
+        from: &NodeId,
+        to: &NodeId,
+        capacity_msat: Msat,
+        now: u64,
+    ) -> (Msat, Msat) {
+        let bounds = self.bounds.lock().unwrap_or_else(|err| err.into_inner());
+        let Some(known) = bounds.get(&directed_channel(short_channel_id, from, to)) else {
+            return (Msat::ZERO, capacity_msat);
+        };
+        let known = self.decayed(known, now);
+        let max = known.max_msat.unwrap_or(capacity_msat).min(capacity_msat);
+        (known.min_msat.min(max), max)
+    }
+
+    /// Apply `f` to the bounds of the channel, decayed to `now`
+    fn update_at(
+        &self,
+        short_channel_id: &ShortChannelId,
+        from: &NodeId,
+        to: &NodeId,
+        now: u64,
+        f: impl FnOnce(&mut Bounds),
+    ) {
+        let mut bounds = self.bounds.lock().unwrap_or_else(|err| err.into_inner());
+        let known = bounds
+            .entry(directed_channel(short_channel_id, from, to))
+            .or_insert(Bounds {
+                min_msat: Msat::ZERO,
+                max_msat: None,
+                updated_at: now,
+            });
+        *known = self.decayed(known, now);
+        f(known);
+    }
+
+    /// The bounds as they are at `now`
+    fn decayed(&self, bounds: &Bounds, now: u64) -> Bounds {
+        let half_life = self.half_life.as_secs().max(1);
+        let elapsed = now.saturating_sub(bounds.updated_at);
+        let decay = 0.5_f64.powf(elapsed as f64 / half_life as f64);
+        Bounds {
+            min_msat: Msat::new((bounds.min_msat.msat() as f64 * decay) as u64),
+            max_msat: bounds
+                .max_msat
+                .map(|max| max.msat() as f64 / decay)
+                // The bound is no more informative than the capacity
+                .filter(|max| *max < u64::MAX as f64)
+                .map(|max| Msat::new(max as u64)),
+            updated_at: now.max(bounds.updated_at),
+        }
+    }
+}
+
+impl Default for LiquidityStore {
+    fn default() -> Self {
+        LiquidityStore::new(DEFAULT_HALF_LIFE)
+    }
+}
+
+/// The CLN `<scid>/<direction>` id of the channel forwarding from `from` to
+/// `to`
+fn directed_channel(short_channel_id: &ShortChannelId, from: &NodeId, to: &NodeId) -> String {
+    format!("{short_channel_id}/{}", u8::from(from > to))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::test_utils::{node, scid};
+    use crate::strategy::{PaymentFailure, RouteHop};
+
+    #[test]
+    fn test_bounds_decay() {
+        let store = LiquidityStore::new(Duration::from_secs(100));
+        store.update_at(
+            &scid("1x1x0"),
+            &node("alice"),
+            &node("bob"),
+            1_000,
+            |bounds| {
+                bounds.min_msat = Msat::new(200_000);
+                bounds.max_msat = Some(Msat::new(600_000));
+            },
+        );
+
+        assert_eq!(
+            store.bounds_at(
+                &scid("1x1x0"),
+                &node("alice"),
+                &node("bob"),
+                Msat::new(1_000_000),
+                1_000
+            ),
+            (Msat::new(200_000), Msat::new(600_000))
+        );
+        assert_eq!(
+            store.bounds_at(
+                &scid("1x1x0"),
+                &node("alice"),
+                &node("bob"),
+                Msat::new(1_000_000),
+                1_100
+            ),
+            (Msat::new(100_000), Msat::new(1_000_000))
+        );
+        assert_eq!(
+            store.bounds_at(
+                &scid("1x1x0"),
+                &node("alice"),
+                &node("bob"),
+                Msat::new(2_000_000),
+                1_100
+            ),
+            (Msat::new(100_000), Msat::new(1_200_000))
+        );
+        // The other direction is unknown
+        assert_eq!(
+            store.bounds_at(
+                &scid("1x1x0"),
+                &node("bob"),
+                &node("alice"),
+                Msat::new(1_000_000),
+                1_000
+            ),
+            (Msat::ZERO, Msat::new(1_000_000))
+        );
+    }
+
+    #[test]
+    fn test_record_payment() {
+        let store = LiquidityStore::default();
+        let part = RoutePart::new(
+            Msat::new(1_000),
+            vec![
+                RouteHop::new(node("bob"), scid("1x1x0"), 24, Msat::new(1_010)),
+                RouteHop::new(node("carol"), scid("2x1x0"), 18, Msat::new(1_000)),
+            ],
+        );
+        let failure = PaymentFailure {
+            erring_index: Some(1),
+            failcode: Some(TEMPORARY_CHANNEL_FAILURE),
+            ..Default::default()
+        };
+        store.record_payment(&node("alice"), &part, &PaymentOutcome::Failure(failure));
+
+        assert_eq!(
+            store.bounds(
+                &scid("1x1x0"),
+                &node("alice"),
+                &node("bob"),
+                Msat::new(10_000)
+            ),
+            (Msat::new(1_010), Msat::new(10_000))
+        );
+        assert_eq!(
+            store.bounds(
+                &scid("2x1x0"),
+                &node("bob"),
+                &node("carol"),
+                Msat::new(10_000)
+            ),
+            (Msat::ZERO, Msat::new(999))
+        );
+
+        store.record_payment(&node("alice"), &part, &PaymentOutcome::Success);
+        assert_eq!(
+            store.bounds(
+                &scid("2x1x0"),
+                &node("bob"),
+                &node("carol"),
+                Msat::new(10_000)
+            ),
+            (Msat::new(1_000), Msat::new(1_000))
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Our own channels, as seen by our node
+//!
+//! The gossip only announces the capacity of a channel, while our node knows
+//! how it is split between us and the peer. The local channels are laid over
+//! the network graph, so we never route through a channel whose capacity is
+//! on the other side.
+
+use std::collections::HashMap;
+
+use crate::amount::Msat;
+use crate::node_id::NodeId;
+use crate::short_channel_id::ShortChannelId;
+
+/// The CLN state of a channel able to send payments
+pub const NORMAL_STATE: &str = "CHANNELD_NORMAL";
+
+/// One of our channels, as reported by CLN `listpeerchannels`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalChannel {
+    pub short_channel_id: ShortChannelId,
+    pub peer_id: NodeId,
+    /// Whether we are connected to the peer
+    pub peer_connected: bool,
+    /// The CLN state of the channel, e.g. `CHANNELD_NORMAL`
+    pub state: String,
+    pub total_msat: Msat,
+    /// The largest HTLC we can send through the channel right now
+    pub spendable_msat: Msat,
+    /// The largest HTLC the peer can send us right now
+    pub receivable_msat: Msat,
+    /// The smallest HTLC the peer accepts from us
+    pub minimum_htlc_out_msat: Msat,
+    /// Whether the channel is unannounced
+    pub private: bool,
+}
+
+impl LocalChannel {
+    /// Whether the channel can send a payment now
+    pub fn is_usable(&self) -> bool {
+        self.peer_connected && self.state == NORMAL_STATE
+    }
+
+    /// The largest amount we can send through the channel now
+    pub fn sendable_msat(&self) -> Msat {
+        if self.is_usable() {
+            self.spendable_msat
+        } else {
+            Msat::ZERO
+        }
+    }
+}
+
+/// Our channels, keyed by short channel id
+///
+/// The channels still opening, without a short channel id, can not be routed
+/// through and are left out.
+#[derive(Debug, Clone, Default)]
+pub struct LocalChannels {
+    channels: HashMap<ShortChannelId, LocalChannel>,
+}
+
+impl LocalChannels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, channel: LocalChannel) {
+        self.channels.insert(channel.short_channel_id, channel);
+    }
+
+    pub fn get(&self, id: &ShortChannelId) -> Option<&LocalChannel> {
+        self.channels.get(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LocalChannel> {
+        self.channels.values()
+    }
+
+    /// Whether we know nothing about our channels, e.g. they were not
+    /// fetched
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+}
+
+impl FromIterator<LocalChannel> for LocalChannels {
+    fn from_iter<I: IntoIterator<Item = LocalChannel>>(iter: I) -> Self {
+        let mut channels = LocalChannels::new();
+        for channel in iter {
+            channels.add(channel);
+        }
+        channels
+    }
+}
@@ -0,0 +1,111 @@
+//! Node ids, the compressed secp256k1 public keys of the nodes
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Result;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use lampo_common::bitcoin::secp256k1::PublicKey;
+
+/// Identifies a node by its public key, in the 33 bytes compressed encoding
+///
+/// The key is validated when the id is built, so every `NodeId` can be
+/// turned back into a `PublicKey`. It is written in lowercase hex, but the
+/// hex is parsed regardless of its case.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId([u8; 33]);
+
+impl NodeId {
+    /// The 33 bytes of the compressed public key
+    pub fn as_bytes(&self) -> &[u8; 33] {
+        &self.0
+    }
+
+    pub fn to_public_key(&self) -> PublicKey {
+        // SAFETY: the bytes were validated as a public key when building the id.
+        PublicKey::from_slice(&self.0).unwrap()
+    }
+}
+
+impl From<PublicKey> for NodeId {
+    fn from(value: PublicKey) -> Self {
+        NodeId(value.serialize())
+    }
+}
+
+impl From<NodeId> for PublicKey {
+    fn from(value: NodeId) -> Self {
+        value.to_public_key()
+    }
+}
+
+impl TryFrom<&[u8]> for NodeId {
+    type Error = anyhow::Error;
+
+    /// Read a compressed public key, as in the gossip messages
+    fn try_from(value: &[u8]) -> Result<Self> {
+        let key = PublicKey::from_slice(value)
+            .map_err(|err| anyhow::anyhow!("Invalid node id `{}`: {err}", hex::encode(value)))?;
+        Ok(NodeId::from(key))
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes =
+            hex::decode(s).map_err(|err| anyhow::anyhow!("Invalid node id `{s}`: {err}"))?;
+        NodeId::try_from(bytes.as_slice())
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId({self})")
+    }
+}
+
+impl Serialize for NodeId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        id.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "022d223620a359a47ff7f7ac447c85c46c923da53389221a0054c11c1e3ca31d59";
+
+    #[test]
+    fn test_encoding() {
+        let id: NodeId = ID.parse().unwrap();
+        assert_eq!(id.to_string(), ID);
+        assert_eq!(ID.to_uppercase().parse::<NodeId>().unwrap(), id);
+        assert_eq!(NodeId::from(id.to_public_key()), id);
+
+        // Not hex, not 33 bytes and not on the curve
+        assert!("alice".parse::<NodeId>().is_err());
+        assert!(ID[..64].parse::<NodeId>().is_err());
+        assert!(format!("04{}", &ID[2..]).parse::<NodeId>().is_err());
+
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{ID}\""));
+        assert_eq!(serde_json::from_str::<NodeId>(&json).unwrap(), id);
+    }
+}
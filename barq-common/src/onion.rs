@@ -0,0 +1,99 @@
+//! The onion payloads Barq builds itself, when `sendpay` can not
+//!
+//! CLN `sendpay` only builds the onion of a plain route to an invoice. The
+//! payments through a blinded path or with custom records are sent with
+//! `createonion` and `sendonion`, from the hops built here.
+//!
+//! See: https://github.com/lightning/bolts/blob/master/04-onion-routing.md#packet-structure
+
+use serde::{Serialize, Serializer};
+
+use crate::node_id::NodeId;
+use crate::strategy::RouteHop;
+
+/// BOLT4 `payload` TLV types
+pub(crate) const AMT_TO_FORWARD: u64 = 2;
+pub(crate) const OUTGOING_CLTV_VALUE: u64 = 4;
+pub(crate) const SHORT_CHANNEL_ID: u64 = 6;
+
+/// A hop of the onion, as expected by CLN `createonion`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OnionHop {
+    pub pubkey: NodeId,
+    /// The payload, prefixed by its length
+    #[serde(serialize_with = "to_hex")]
+    pub payload: Vec<u8>,
+}
+
+/// The hops of `route` forwarding the payment, i.e. all but the last one
+///
+/// The delays of the route are relative to `base_expiry`, the current block
+/// height plus one as CLN `sendpay` does.
+pub(crate) fn forward_hops(route: &[RouteHop], base_expiry: u64) -> Vec<OnionHop> {
+    route
+        .iter()
+        .zip(route.iter().skip(1))
+        .map(|(hop, next)| {
+            let mut payload = TlvStream::default();
+            payload.truncated(AMT_TO_FORWARD, next.amount_msat.msat());
+            payload.truncated(OUTGOING_CLTV_VALUE, base_expiry + next.delay as u64);
+            payload.record(SHORT_CHANNEL_ID, &next.channel.to_u64().to_be_bytes());
+            OnionHop {
+                pubkey: hop.id,
+                payload: payload.into_payload(),
+            }
+        })
+        .collect()
+}
+
+/// A BOLT1 TLV stream, written in the order of the records
+#[derive(Default)]
+pub(crate) struct TlvStream(Vec<u8>);
+
+impl TlvStream {
+    pub(crate) fn record(&mut self, kind: u64, value: &[u8]) {
+        write_bigsize(&mut self.0, kind);
+        write_bigsize(&mut self.0, value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    /// A `tu64` record, without the leading zero bytes
+    pub(crate) fn truncated(&mut self, kind: u64, value: u64) {
+        let bytes = value.to_be_bytes();
+        let start = bytes.iter().take_while(|byte| **byte == 0).count();
+        self.record(kind, &bytes[start..]);
+    }
+
+    /// The onion payload, prefixed by its length
+    pub(crate) fn into_payload(self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(self.0.len() + 3);
+        write_bigsize(&mut payload, self.0.len() as u64);
+        payload.extend(self.0);
+        payload
+    }
+}
+
+/// Write `value` in the BOLT1 `bigsize` encoding
+///
+/// See: https://github.com/lightning/bolts/blob/master/01-messaging.md#appendix-a-bigsize-test-vectors
+fn write_bigsize(buffer: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0xfc => buffer.push(value as u8),
+        0xfd..=0xffff => {
+            buffer.push(0xfd);
+            buffer.extend((value as u16).to_be_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            buffer.push(0xfe);
+            buffer.extend((value as u32).to_be_bytes());
+        }
+        _ => {
+            buffer.push(0xff);
+            buffer.extend(value.to_be_bytes());
+        }
+    }
+}
+
+pub(crate) fn to_hex<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(bytes))
+}
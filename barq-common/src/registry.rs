@@ -0,0 +1,207 @@
+//! Registry of the routing strategies known by Barq
+//!
+//! Strategies are registered by name together with a factory, so the plugin
+//! can look them up at runtime and new strategies can be added without
+//! touching the code that runs them.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use lampo_common::conf::Network;
+
+use crate::algorithms::dijkstra::Dijkstra;
+use crate::algorithms::direct::Direct;
+use crate::algorithms::mincostflow::MinCostFlow;
+use crate::algorithms::probabilistic::scorer::ScorerStore;
+use crate::algorithms::probabilistic::LDKRoutingStrategy;
+use crate::strategy::Strategy;
+
+/// Name of the strategy used when none is selected
+pub const DEFAULT_STRATEGY: &str = "direct";
+
+/// Name selecting the first strategy of `AUTO_STRATEGY_CHAIN` able to route
+pub const AUTO_STRATEGY: &str = "auto";
+
+/// Strategies tried in order by the `auto` mode, from the cheapest to run to
+/// the most complete
+pub const AUTO_STRATEGY_CHAIN: [&str; 3] = ["direct", "dijkstra", "probabilistic"];
+
+/// Information handed to the factories to build a strategy
+pub struct StrategyContext {
+    pub network: Network,
+    /// Directory where a strategy can keep its own data
+    pub root_path: String,
+    /// The LDK scorer shared by all the payments
+    pub scorer: Arc<ScorerStore>,
+}
+
+/// Function building a new instance of a strategy
+pub type StrategyFactory = Box<dyn Fn(&StrategyContext) -> Box<dyn Strategy> + Send + Sync>;
+
+/// A strategy registered in the `StrategyRegistry`
+pub struct StrategyEntry {
+    pub name: String,
+    pub description: String,
+    /// Whether the strategy needs a network graph with peer-to-peer
+    /// information (e.g., gossip map)
+    pub needs_p2p_info: bool,
+    factory: StrategyFactory,
+}
+
+impl StrategyEntry {
+    /// Build a new instance of the strategy
+    pub fn build(&self, context: &StrategyContext) -> Box<dyn Strategy> {
+        (self.factory)(context)
+    }
+}
+
+/// Registry of the routing strategies, looked up by name
+pub struct StrategyRegistry {
+    entries: BTreeMap<String, StrategyEntry>,
+}
+
+impl StrategyRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        StrategyRegistry {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Register a new strategy under `name`
+    ///
+    /// Names are case insensitive, and registering the same name twice is an
+    /// error.
+    pub fn register<F>(
+        &mut self,
+        name: &str,
+        description: &str,
+        needs_p2p_info: bool,
+        factory: F,
+    ) -> Result<()>
+    where
+        F: Fn(&StrategyContext) -> Box<dyn Strategy> + Send + Sync + 'static,
+    {
+        let name = name.to_lowercase();
+        if name == AUTO_STRATEGY {
+            anyhow::bail!("Strategy name `{name}` is reserved");
+        }
+        if self.entries.contains_key(&name) {
+            anyhow::bail!("Strategy `{name}` is already registered");
+        }
+        self.entries.insert(
+            name.clone(),
+            StrategyEntry {
+                name,
+                description: description.to_string(),
+                needs_p2p_info,
+                factory: Box::new(factory),
+            },
+        );
+        Ok(())
+    }
+
+    /// Get the strategy registered under `name`
+    pub fn get(&self, name: &str) -> Result<&StrategyEntry> {
+        self.entries
+            .get(&name.to_lowercase())
+            .ok_or_else(|| anyhow::anyhow!("Strategy `{name}` not found"))
+    }
+
+    /// Resolve `name` into the strategies to try, in order
+    ///
+    /// `auto` resolves to the registered strategies of `AUTO_STRATEGY_CHAIN`,
+    /// any other name to the single strategy registered under it.
+    pub fn resolve(&self, name: &str) -> Result<Vec<&StrategyEntry>> {
+        if name.to_lowercase() != AUTO_STRATEGY {
+            return Ok(vec![self.get(name)?]);
+        }
+        Ok(AUTO_STRATEGY_CHAIN
+            .iter()
+            .filter_map(|name| self.entries.get(*name))
+            .collect())
+    }
+
+    /// All the registered strategies, sorted by name
+    pub fn entries(&self) -> impl Iterator<Item = &StrategyEntry> {
+        self.entries.values()
+    }
+}
+
+impl Default for StrategyRegistry {
+    /// Create a registry with all the strategies shipped with Barq
+    fn default() -> Self {
+        let mut registry = StrategyRegistry::new();
+        // SAFETY: the built-in strategies have all different names.
+        registry
+            .register("direct", "Pay a node we have a channel with", false, |_| {
+                Box::new(Direct::new())
+            })
+            .unwrap();
+        registry
+            .register(
+                "dijkstra",
+                "Route along the path with the lowest fee",
+                false,
+                |_| Box::new(Dijkstra::new()),
+            )
+            .unwrap();
+        registry
+            .register(
+                "mincostflow",
+                "Split the payment along the min-cost flow of fees and liquidity uncertainty",
+                false,
+                |_| Box::new(MinCostFlow::new()),
+            )
+            .unwrap();
+        registry
+            .register(
+                "probabilistic",
+                "Route with the LDK router and probabilistic scorer",
+                true,
+                |context| {
+                    Box::new(LDKRoutingStrategy::with_scorer(
+                        context.network,
+                        context.scorer.clone(),
+                    ))
+                },
+            )
+            .unwrap();
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_by_name() {
+        let mut registry = StrategyRegistry::default();
+        assert!(registry.get("Dijkstra").is_ok());
+        assert!(registry.get("unknown").is_err());
+        assert!(registry
+            .register("direct", "Another direct", false, |_| Box::new(
+                Direct::new()
+            ))
+            .is_err());
+        assert!(registry
+            .register("auto", "Not a strategy", false, |_| Box::new(Direct::new()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_resolve_auto() {
+        let registry = StrategyRegistry::default();
+        let chain: Vec<_> = registry
+            .resolve("auto")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .collect();
+        assert_eq!(chain, AUTO_STRATEGY_CHAIN);
+        assert_eq!(registry.resolve("mincostflow").unwrap().len(), 1);
+    }
+}
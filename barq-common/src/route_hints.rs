@@ -0,0 +1,223 @@
+//! Route hints, the private channels an invoice tells us to reach its payee
+//!
+//! A payee behind private channels is not in the gossip, so the invoice lists
+//! the last hops of some routes to it. The hinted channels are laid over the
+//! network graph, so every strategy can route through them.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Msat;
+use crate::graph::{Channel, ChannelPolicy, NetworkGraph, Node};
+use crate::node_id::NodeId;
+use crate::short_channel_id::ShortChannelId;
+
+/// The capacity assumed for a hinted channel, that the invoice does not tell:
+/// the largest channel allowed without `option_support_large_channel`, i.e.
+/// 16777215 sat
+pub const HINT_CAPACITY: Msat = Msat::new(16_777_215_000);
+
+/// A hop of a route hint, as decoded by CLN `decode`
+///
+/// See: https://docs.corelightning.org/reference/lightning-decode#return-value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteHintHop {
+    /// The node forwarding through the channel
+    pub pubkey: NodeId,
+    pub short_channel_id: ShortChannelId,
+    pub fee_base_msat: Msat,
+    pub fee_proportional_millionths: u64,
+    pub cltv_expiry_delta: u64,
+}
+
+impl RouteHintHop {
+    /// The policy of the node to forward through the channel
+    pub fn policy(&self) -> ChannelPolicy {
+        ChannelPolicy::new(
+            self.cltv_expiry_delta,
+            self.fee_base_msat,
+            self.fee_proportional_millionths,
+        )
+    }
+}
+
+/// The last hops of a route to the payee, each one forwarding to the next and
+/// the last one to the payee
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteHint(pub Vec<RouteHintHop>);
+
+impl RouteHint {
+    /// The hops with the node they forward to, when paying `dest_pubkey`
+    pub fn edges<'a>(
+        &'a self,
+        dest_pubkey: &'a NodeId,
+    ) -> impl Iterator<Item = (&'a RouteHintHop, &'a NodeId)> {
+        let next = self.0.iter().skip(1).map(|hop| &hop.pubkey);
+        self.0.iter().zip(next.chain(std::iter::once(dest_pubkey)))
+    }
+}
+
+/// A network graph with the channels of the route hints on top
+///
+/// The graph underneath is shared and never modified: the nodes and the
+/// channels touched by the hints are copied in the overlay.
+pub struct HintedGraph {
+    graph: Arc<dyn NetworkGraph>,
+    nodes: HashMap<NodeId, Node>,
+    channels: HashMap<ShortChannelId, Channel>,
+}
+
+impl HintedGraph {
+    pub fn new(graph: Arc<dyn NetworkGraph>, hints: &[RouteHint], dest_pubkey: &NodeId) -> Self {
+        let mut hinted = HintedGraph {
+            graph,
+            nodes: HashMap::new(),
+            channels: HashMap::new(),
+        };
+        for (hop, to) in hints.iter().flat_map(|hint| hint.edges(dest_pubkey)) {
+            hinted.add_hop(hop, to);
+        }
+        hinted
+    }
+
+    fn add_hop(&mut self, hop: &RouteHintHop, to: &NodeId) {
+        let from = hop.pubkey;
+        if from == *to {
+            return;
+        }
+        let mut channel = match self.get_channel(&hop.short_channel_id) {
+            Some(channel) if channel.counterparty(&from) == Some(*to) => channel.clone(),
+            // Unknown, or known between other nodes: the hint wins
+            _ => Channel::new(
+                hop.short_channel_id,
+                from.min(*to),
+                from.max(*to),
+                HINT_CAPACITY,
+            ),
+        };
+        // The gossip knows better than the invoice when it announces the
+        // policy
+        if channel.policy_from(&from).is_none() {
+            let direction = u8::from(from > *to);
+            channel.set_policy(direction, hop.policy());
+        }
+        let graph = &self.graph;
+        for node_id in [from, *to] {
+            self.nodes
+                .entry(node_id)
+                .or_insert_with(|| {
+                    graph
+                        .get_node(&node_id)
+                        .cloned()
+                        .unwrap_or_else(|| Node::new(node_id))
+                })
+                .add_channel(&channel);
+        }
+        self.channels.insert(channel.short_channel_id, channel);
+    }
+}
+
+impl NetworkGraph for HintedGraph {
+    fn get_channels(&self) -> Vec<&Channel> {
+        let mut channels = self
+            .graph
+            .get_channels()
+            .into_iter()
+            .filter(|channel| !self.channels.contains_key(&channel.short_channel_id))
+            .collect::<Vec<_>>();
+        channels.extend(self.channels.values());
+        channels
+    }
+
+    fn get_nodes(&self) -> Vec<&Node> {
+        let mut nodes = self
+            .graph
+            .get_nodes()
+            .into_iter()
+            .filter(|node| !self.nodes.contains_key(&node.id))
+            .collect::<Vec<_>>();
+        nodes.extend(self.nodes.values());
+        nodes
+    }
+
+    fn get_node(&self, id: &NodeId) -> Option<&Node> {
+        self.nodes.get(id).or_else(|| self.graph.get_node(id))
+    }
+
+    fn get_channel(&self, id: &ShortChannelId) -> Option<&Channel> {
+        self.channels.get(id).or_else(|| self.graph.get_channel(id))
+    }
+
+    /// Only the hinted channels can be removed, the graph underneath is
+    /// shared
+    fn remove_channel(&mut self, id: &ShortChannelId) -> Option<Channel> {
+        let channel = self.channels.remove(id)?;
+        for node_id in [&channel.node1, &channel.node2] {
+            if let Some(node) = self.nodes.get_mut(node_id) {
+                node.remove_channel(id);
+            }
+        }
+        Some(channel)
+    }
+
+    /// Only the nodes touched by the hints can be removed, the graph
+    /// underneath is shared
+    fn remove_node(&mut self, id: &NodeId) -> Option<Node> {
+        let node = self.nodes.remove(id)?;
+        for channel in &node.channels {
+            self.remove_channel(&channel.short_channel_id);
+        }
+        Some(node)
+    }
+
+    fn has_p2p_info(&self) -> bool {
+        self.graph.has_p2p_info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::test_utils::{node, scid, TestGraph};
+
+    fn hop(pubkey: &str, id: &str, fee_base_msat: u64) -> RouteHintHop {
+        RouteHintHop {
+            pubkey: node(pubkey),
+            short_channel_id: scid(id),
+            fee_base_msat: Msat::new(fee_base_msat),
+            fee_proportional_millionths: 10,
+            cltv_expiry_delta: 40,
+        }
+    }
+
+    #[test]
+    fn test_hinted_channels() {
+        let mut graph = TestGraph::new();
+        graph.add_channel("1x1x0", "alice", "bob", 1_000_000, (6, 0, 0), (6, 0, 0));
+        let hints = [RouteHint(vec![
+            hop("bob", "2x1x0", 1_000),
+            hop("carol", "3x1x0", 2_000),
+        ])];
+        let graph = HintedGraph::new(Arc::new(graph), &hints, &node("dave"));
+
+        // The payee is reached through the private channels
+        let channel = graph.get_channel(&scid("3x1x0")).unwrap();
+        assert_eq!(channel.counterparty(&node("carol")), Some(node("dave")));
+        assert_eq!(
+            channel
+                .policy_from(&node("carol"))
+                .unwrap()
+                .base_fee_millisatoshi,
+            Msat::new(2_000)
+        );
+        assert!(channel.policy_from(&node("dave")).is_none());
+        assert_eq!(graph.get_node(&node("dave")).unwrap().channels.len(), 1);
+
+        // Bob keeps his public channel next to the private one
+        assert_eq!(graph.get_node(&node("bob")).unwrap().channels.len(), 2);
+        assert_eq!(graph.get_channels().len(), 3);
+        assert_eq!(graph.get_nodes().len(), 4);
+    }
+}
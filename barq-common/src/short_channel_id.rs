@@ -0,0 +1,175 @@
+//! Short channel ids, as defined by BOLT7
+//!
+//! See: https://github.com/lightning/bolts/blob/master/07-routing-gossip.md#definition-of-short_channel_id
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Result;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Identifies a channel by the position of its funding output in the chain
+///
+/// It is encoded as a `u64` made of the block height (3 bytes), the index of
+/// the transaction in the block (3 bytes) and the index of the output (2
+/// bytes), and written `BLOCKxTXxOUTPUT` like CLN does (e.g. `103x1x0`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ShortChannelId(u64);
+
+impl ShortChannelId {
+    /// Build the short channel id from its parts, that must fit in 3, 3 and 2
+    /// bytes
+    pub fn new(block_height: u32, tx_index: u32, output_index: u16) -> Result<Self> {
+        if block_height > 0xFFFFFF || tx_index > 0xFFFFFF {
+            anyhow::bail!(
+                "Invalid short channel id `{block_height}x{tx_index}x{output_index}`: the block height and the transaction index must fit in 3 bytes"
+            );
+        }
+        Ok(ShortChannelId(
+            (block_height as u64) << 40 | (tx_index as u64) << 16 | output_index as u64,
+        ))
+    }
+
+    /// Height of the block including the funding transaction
+    pub fn block_height(&self) -> u32 {
+        (self.0 >> 40) as u32
+    }
+
+    /// Index of the funding transaction in the block
+    pub fn tx_index(&self) -> u32 {
+        ((self.0 >> 16) & 0xFFFFFF) as u32
+    }
+
+    /// Index of the funding output in the transaction
+    pub fn output_index(&self) -> u16 {
+        (self.0 & 0xFFFF) as u16
+    }
+
+    /// The `u64` encoding, as used on the wire and by LDK
+    pub fn to_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for ShortChannelId {
+    fn from(value: u64) -> Self {
+        ShortChannelId(value)
+    }
+}
+
+impl From<ShortChannelId> for u64 {
+    fn from(value: ShortChannelId) -> Self {
+        value.0
+    }
+}
+
+impl TryFrom<&[u8]> for ShortChannelId {
+    type Error = anyhow::Error;
+
+    /// Read the 8 big-endian bytes of a gossip message
+    fn try_from(value: &[u8]) -> Result<Self> {
+        let bytes: [u8; 8] = value
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid short channel id of {} bytes", value.len()))?;
+        Ok(ShortChannelId(u64::from_be_bytes(bytes)))
+    }
+}
+
+impl FromStr for ShortChannelId {
+    type Err = anyhow::Error;
+
+    /// Parse either the `103x1x0` notation or the `u64` encoding
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid short channel id `{s}`");
+        let parts = s.split('x').collect::<Vec<_>>();
+        match parts.as_slice() {
+            [scid] => scid
+                .parse::<u64>()
+                .map(ShortChannelId)
+                .map_err(|_| invalid()),
+            [block, tx, output] => ShortChannelId::new(
+                block.parse().map_err(|_| invalid())?,
+                tx.parse().map_err(|_| invalid())?,
+                output.parse().map_err(|_| invalid())?,
+            ),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for ShortChannelId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{}x{}",
+            self.block_height(),
+            self.tx_index(),
+            self.output_index()
+        )
+    }
+}
+
+impl Serialize for ShortChannelId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ShortChannelId {
+    /// Accept the `103x1x0` notation, and the `u64` encoding either as a
+    /// number or as a string
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ShortChannelIdVisitor;
+
+        impl Visitor<'_> for ShortChannelIdVisitor {
+            type Value = ShortChannelId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a short channel id")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+                Ok(ShortChannelId(value))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                value.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(ShortChannelIdVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        let scid: ShortChannelId = "103x1x0".parse().unwrap();
+        assert_eq!(scid.block_height(), 103);
+        assert_eq!(scid.tx_index(), 1);
+        assert_eq!(scid.output_index(), 0);
+        assert_eq!(scid.to_u64(), 113_249_697_726_464);
+        assert_eq!(ShortChannelId::from(113_249_697_726_464), scid);
+        assert_eq!("113249697726464".parse::<ShortChannelId>().unwrap(), scid);
+        assert_eq!(
+            ShortChannelId::try_from(&scid.to_u64().to_be_bytes()[..]).unwrap(),
+            scid
+        );
+        assert!("16777216x1x0".parse::<ShortChannelId>().is_err());
+        assert!("103x1".parse::<ShortChannelId>().is_err());
+
+        assert_eq!(serde_json::to_string(&scid).unwrap(), r#""103x1x0""#);
+        assert_eq!(
+            serde_json::from_str::<ShortChannelId>(r#""103x1x0""#).unwrap(),
+            scid
+        );
+        assert_eq!(
+            serde_json::from_str::<ShortChannelId>("113249697726464").unwrap(),
+            scid
+        );
+    }
+}
@@ -1,35 +1,19 @@
-use std::str::FromStr;
+use std::collections::HashSet;
+use std::sync::Arc;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
 use lampo_common::conf::Network;
 
-use crate::graph::NetworkGraph;
-
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub enum StrategyKind {
-    Direct,
-    Probabilistic,
-}
-
-impl FromStr for StrategyKind {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> anyhow::Result<Self> {
-        match s.to_lowercase().as_str() {
-            "direct" => Ok(Self::Direct),
-            "probabilistic" => Ok(Self::Probabilistic),
-            _ => anyhow::bail!("Strategy `{s}` not found"),
-        }
-    }
-}
-
-impl Default for StrategyKind {
-    fn default() -> Self {
-        Self::Direct
-    }
-}
+use crate::amount::Msat;
+use crate::graph::{Channel, NetworkGraph};
+use crate::inflight::InFlightHtlcs;
+use crate::liquidity::LiquidityStore;
+use crate::local_channels::{LocalChannel, LocalChannels};
+use crate::node_id::NodeId;
+use crate::route_hints::RouteHint;
+use crate::short_channel_id::ShortChannelId;
 
 /// The `Strategy` trait defines an interface for routing strategies used within
 /// Barq.
@@ -44,20 +28,48 @@ pub trait Strategy {
     /// Route the payment using the strategy
     /// return error if execution unsuccessful
     fn route(&self, input: &RouteInput) -> Result<RouteOutput>;
+
+    /// Called with the outcome of each part of a payment routed by the
+    /// strategy, so it can learn from it
+    ///
+    /// Does nothing by default.
+    fn on_payment_result(&self, _part: &RoutePart, _outcome: &PaymentOutcome) {}
+}
+
+/// What happened to a part of a payment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaymentOutcome {
+    /// The part reached the destination
+    Success,
+    Failure(PaymentFailure),
+}
+
+/// Where and why a part of a payment failed, as reported by the erring node
+///
+/// See: https://github.com/lightning/bolts/blob/master/04-onion-routing.md#returning-errors
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentFailure {
+    /// Index of the node reporting the failure along the path, 0 being us
+    pub erring_index: Option<u64>,
+    pub erring_node: Option<NodeId>,
+    pub erring_channel: Option<ShortChannelId>,
+    /// The BOLT4 failure code
+    pub failcode: Option<u16>,
 }
 
 /// Represents a single hop in a route between two nodes
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct RouteHop {
-    pub id: String,
-    pub channel: String,
+    pub id: NodeId,
+    pub channel: ShortChannelId,
     pub delay: u32,
-    pub amount_msat: u64,
+    pub amount_msat: Msat,
 }
 
 impl RouteHop {
     /// Create a new `RouteHop` instance with the provided fields
-    pub fn new(id: String, channel: String, delay: u32, amount_msat: u64) -> Self {
+    pub fn new(id: NodeId, channel: ShortChannelId, delay: u32, amount_msat: Msat) -> Self {
         RouteHop {
             id,
             channel,
@@ -67,25 +79,311 @@ impl RouteHop {
     }
 }
 
+/// Constraints that every route found by a strategy must honour
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RouteConstraints {
+    /// Maximum total fee paid to the intermediate hops
+    pub max_fee_msat: Option<Msat>,
+    /// Maximum total fee, as a percentage of the amount delivered
+    pub max_fee_percent: Option<f64>,
+    /// Maximum CLTV delay of the route, including the final one
+    pub max_cltv: Option<u64>,
+    /// Maximum number of hops of each part
+    pub max_hops: Option<usize>,
+    /// Maximum number of parts the payment can be split in
+    pub max_parts: Option<usize>,
+    pub excluded_nodes: HashSet<NodeId>,
+    pub excluded_channels: HashSet<ShortChannelId>,
+    /// The channel every part must start with
+    pub first_hop: Option<ShortChannelId>,
+    /// The node every part must reach the destination through
+    pub last_hop: Option<NodeId>,
+}
+
+impl RouteConstraints {
+    /// Maximum total fee allowed to deliver `amount_msat`, if any
+    pub fn max_fee_for(&self, amount_msat: Msat) -> Option<Msat> {
+        let percent = self
+            .max_fee_percent
+            .map(|percent| Msat::new((amount_msat.msat() as f64 * percent / 100.0) as u64));
+        match (self.max_fee_msat, percent) {
+            (Some(absolute), Some(percent)) => Some(absolute.min(percent)),
+            (absolute, percent) => absolute.or(percent),
+        }
+    }
+}
+
 /// Represents input data required for routing a payment
 pub struct RouteInput {
-    pub src_pubkey: String,
-    pub dest_pubkey: String,
+    pub src_pubkey: NodeId,
+    pub dest_pubkey: NodeId,
     pub network: Network,
-    pub amount_msat: u64,
+    pub amount_msat: Msat,
     pub cltv: u64,
     /// The network graph used for routing
-    pub graph: Box<dyn NetworkGraph>,
+    pub graph: Arc<dyn NetworkGraph>,
     /// Whether to use the rapid gossip sync map to build the network graph
     /// (Only applicable when the probabilistic strategy is selected)
     ///
     /// If not provided, we will try to use CLN gossip map to build the network
     /// graph
     pub use_rapid_gossip_sync: bool,
+    pub constraints: RouteConstraints,
+    /// What we know about the liquidity of the channels
+    pub liquidity: Arc<LiquidityStore>,
+    /// Our own channels, with the balance we can spend on each
+    pub local_channels: Arc<LocalChannels>,
+    /// The parts we sent that are not resolved yet
+    pub inflight: Arc<InFlightHtlcs>,
+    /// The private channels the invoice tells us to reach the destination
+    ///
+    /// The graph already has them, the strategies building their own graph
+    /// (e.g. from rapid gossip sync) must add them.
+    pub route_hints: Vec<RouteHint>,
+}
+
+impl RouteInput {
+    /// Our own channel `channel` when sending from `from`, if we know it
+    pub fn local_channel(&self, channel: &Channel, from: &NodeId) -> Option<&LocalChannel> {
+        if *from != self.src_pubkey {
+            return None;
+        }
+        self.local_channels.get(&channel.short_channel_id)
+    }
+
+    /// The largest amount `channel` can forward from `from`
+    ///
+    /// On our own channels it is the balance we can spend, when we know it,
+    /// and otherwise the capacity left by our parts in flight.
+    pub fn capacity_from(&self, channel: &Channel, from: &NodeId) -> Msat {
+        match self.local_channel(channel, from) {
+            // Our node already takes its pending HTLCs out of the balance
+            Some(local) => local.sendable_msat(),
+            None => channel
+                .capacity
+                .saturating_sub(self.inflight.used_msat(&channel.short_channel_id, from)),
+        }
+    }
+
+    /// The bounds of the liquidity `channel` can forward from `from` to `to`
+    ///
+    /// Our node knows the balance of our own channels exactly, on the other
+    /// channels the amount of our parts in flight is not available.
+    pub fn liquidity_bounds(&self, channel: &Channel, from: &NodeId, to: &NodeId) -> (Msat, Msat) {
+        if let Some(local) = self.local_channel(channel, from) {
+            return (local.sendable_msat(), local.sendable_msat());
+        }
+        let used = self.inflight.used_msat(&channel.short_channel_id, from);
+        let (min_msat, max_msat) =
+            self.liquidity
+                .bounds(&channel.short_channel_id, from, to, channel.capacity);
+        (min_msat.saturating_sub(used), max_msat.saturating_sub(used))
+    }
+
+    /// Whether the constraints allow forwarding the payment through the
+    /// channel `channel_id` from `from` to `to`
+    pub fn allows_hop(&self, channel_id: &ShortChannelId, from: &NodeId, to: &NodeId) -> bool {
+        let constraints = &self.constraints;
+        if constraints.excluded_channels.contains(channel_id)
+            || constraints.excluded_nodes.contains(from)
+            || constraints.excluded_nodes.contains(to)
+        {
+            return false;
+        }
+        if *from == self.src_pubkey
+            && constraints
+                .first_hop
+                .as_ref()
+                .is_some_and(|first_hop| first_hop != channel_id)
+        {
+            return false;
+        }
+        *to != self.dest_pubkey
+            || constraints
+                .last_hop
+                .as_ref()
+                .is_none_or(|last_hop| last_hop == from)
+    }
+
+    /// Check that the route found by a strategy honours the constraints
+    pub fn check_constraints(&self, output: &RouteOutput) -> Result<()> {
+        let constraints = &self.constraints;
+        if let Some(max_fee_msat) = constraints.max_fee_for(output.amount_msat()) {
+            if output.fee_msat() > max_fee_msat {
+                anyhow::bail!(
+                    "Route fee `{}` exceeds the maximum fee `{max_fee_msat}`",
+                    output.fee_msat()
+                );
+            }
+        }
+        if let Some(max_cltv) = constraints.max_cltv {
+            if output.total_delay() as u64 > max_cltv {
+                anyhow::bail!(
+                    "Route delay `{}` exceeds the maximum delay `{max_cltv}`",
+                    output.total_delay()
+                );
+            }
+        }
+        if let Some(max_parts) = constraints.max_parts {
+            if output.parts.len() > max_parts {
+                anyhow::bail!(
+                    "Route with `{}` parts exceeds the maximum of `{max_parts}` parts",
+                    output.parts.len()
+                );
+            }
+        }
+        for part in &output.parts {
+            if let Some(max_hops) = constraints.max_hops {
+                if part.path.len() > max_hops {
+                    anyhow::bail!(
+                        "Route with `{}` hops exceeds the maximum of `{max_hops}` hops",
+                        part.path.len()
+                    );
+                }
+            }
+            let mut from = &self.src_pubkey;
+            for hop in &part.path {
+                if !self.allows_hop(&hop.channel, from, &hop.id) {
+                    anyhow::bail!(
+                        "Route through channel `{}` to `{}` is not allowed",
+                        hop.channel,
+                        hop.id
+                    );
+                }
+                from = &hop.id;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Represents a single part of a payment, delivering `amount_msat` to the
+/// destination along `path`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RoutePart {
+    /// The amount delivered to the destination by this part
+    pub amount_msat: Msat,
+    pub path: Vec<RouteHop>,
+}
+
+impl RoutePart {
+    /// Create a new `RoutePart` instance with the provided fields
+    pub fn new(amount_msat: Msat, path: Vec<RouteHop>) -> Self {
+        RoutePart { amount_msat, path }
+    }
+
+    /// Build the part delivering `amount_msat` with a final CLTV of `cltv`
+    /// through `channels`, the first one being a channel of `src`.
+    ///
+    /// The amount and the delay of each hop are computed backwards from the
+    /// destination, charging at each hop the fee and the CLTV delta of the
+    /// policy of the node forwarding the payment.
+    pub fn from_channels(
+        src: &NodeId,
+        channels: &[&Channel],
+        amount_msat: Msat,
+        cltv: u64,
+    ) -> Result<Self> {
+        // The nodes along the path, starting from the source
+        let mut nodes = vec![*src];
+        for channel in channels {
+            // SAFETY: `nodes` is never empty.
+            let last = nodes.last().unwrap();
+            let next = channel.counterparty(last).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Channel `{}` is not a channel of `{last}`",
+                    channel.short_channel_id
+                )
+            })?;
+            nodes.push(next);
+        }
+
+        let mut path = Vec::with_capacity(channels.len());
+        let mut amount = amount_msat;
+        let mut delay = cltv;
+        for (i, channel) in channels.iter().enumerate().rev() {
+            let policy = channel.policy_from(&nodes[i]).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown policy of `{}` for channel `{}`",
+                    nodes[i],
+                    channel.short_channel_id
+                )
+            })?;
+            if !policy.can_forward(amount) {
+                anyhow::bail!(
+                    "Channel `{}` can not forward `{amount}`",
+                    channel.short_channel_id
+                );
+            }
+            path.push(RouteHop::new(
+                nodes[i + 1],
+                channel.short_channel_id,
+                delay as u32,
+                amount,
+            ));
+            // We do not pay any fee to ourselves for the first hop
+            if i > 0 {
+                amount = amount.saturating_add(policy.fee_msat(amount));
+                delay = delay.saturating_add(policy.delay);
+            }
+        }
+        path.reverse();
+
+        Ok(RoutePart::new(amount_msat, path))
+    }
+
+    /// Fee paid to the intermediate hops of this part
+    pub fn fee_msat(&self) -> Msat {
+        self.path
+            .first()
+            .map(|hop| hop.amount_msat.saturating_sub(self.amount_msat))
+            .unwrap_or_default()
+    }
+
+    /// CLTV delay of this part, that is the delay of the first hop
+    pub fn delay(&self) -> u32 {
+        self.path.first().map(|hop| hop.delay).unwrap_or_default()
+    }
 }
 
 /// Represents the output of a routing strategy
-#[derive(Serialize, Deserialize)]
+///
+/// A payment can be split in multiple parts (multi-part payment), each one
+/// with its own path and amount.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RouteOutput {
-    pub path: Vec<RouteHop>,
+    pub parts: Vec<RoutePart>,
+}
+
+impl RouteOutput {
+    /// Create an output made of a single part delivering `amount_msat`
+    pub fn single_part(amount_msat: Msat, path: Vec<RouteHop>) -> Self {
+        RouteOutput {
+            parts: vec![RoutePart::new(amount_msat, path)],
+        }
+    }
+
+    /// Whether the strategy did not find any path
+    pub fn is_empty(&self) -> bool {
+        self.parts.iter().all(|part| part.path.is_empty())
+    }
+
+    /// Total amount delivered to the destination by all the parts
+    pub fn amount_msat(&self) -> Msat {
+        self.parts.iter().map(|part| part.amount_msat).sum()
+    }
+
+    /// Total fee paid to the intermediate hops by all the parts
+    pub fn fee_msat(&self) -> Msat {
+        self.parts.iter().map(RoutePart::fee_msat).sum()
+    }
+
+    /// Highest CLTV delay among all the parts
+    pub fn total_delay(&self) -> u32 {
+        self.parts
+            .iter()
+            .map(RoutePart::delay)
+            .max()
+            .unwrap_or_default()
+    }
 }
@@ -0,0 +1,187 @@
+//! Validation of the routes found by the strategies before paying them
+//!
+//! A strategy can be buggy, so we check its routes against the network graph
+//! it routed on: a route rejected here would have failed in the onion anyway,
+//! but with a much less clear error.
+
+use anyhow::Result;
+
+use crate::amount::Msat;
+use crate::graph::NetworkGraph;
+use crate::node_id::NodeId;
+use crate::strategy::{RouteOutput, RoutePart};
+
+/// Check every part of `output`, sent by `src_pubkey`, against `graph`, and
+/// that the parts deliver `amount_msat` together
+///
+/// See `validate_part`.
+pub fn validate_route(
+    graph: &dyn NetworkGraph,
+    src_pubkey: &NodeId,
+    output: &RouteOutput,
+    amount_msat: Msat,
+    min_final_cltv: u64,
+) -> Result<()> {
+    validate_amount(output, amount_msat)?;
+    for part in &output.parts {
+        validate_part(graph, src_pubkey, part, min_final_cltv)?;
+    }
+    Ok(())
+}
+
+/// Check that the parts of `output` deliver `amount_msat` together
+pub fn validate_amount(output: &RouteOutput, amount_msat: Msat) -> Result<()> {
+    if output.amount_msat() != amount_msat {
+        anyhow::bail!(
+            "The route delivers `{}` instead of `{amount_msat}`",
+            output.amount_msat()
+        );
+    }
+    Ok(())
+}
+
+/// Check that `part`, sent by `src_pubkey`, can be forwarded on `graph`
+///
+/// Each hop must go through a channel of the previous node and reach the
+/// next one, with an amount and a delay that cover the fee and the CLTV delta
+/// of the policy of the previous node, within the capacity and the HTLC
+/// limits of the channel. The last hop must deliver the amount of the part
+/// with at least `min_final_cltv`.
+pub fn validate_part(
+    graph: &dyn NetworkGraph,
+    src_pubkey: &NodeId,
+    part: &RoutePart,
+    min_final_cltv: u64,
+) -> Result<()> {
+    let Some(last) = part.path.last() else {
+        anyhow::bail!("The route has no hops");
+    };
+    if last.amount_msat < part.amount_msat {
+        anyhow::bail!(
+            "The last hop delivers `{}` instead of `{}`",
+            last.amount_msat,
+            part.amount_msat
+        );
+    }
+    if (last.delay as u64) < min_final_cltv {
+        anyhow::bail!(
+            "The last hop delay `{}` is below the final CLTV `{min_final_cltv}`",
+            last.delay
+        );
+    }
+
+    let mut from = src_pubkey;
+    for (i, hop) in part.path.iter().enumerate() {
+        let channel = graph
+            .get_channel(&hop.channel)
+            .ok_or_else(|| anyhow::anyhow!("Unknown channel `{}`", hop.channel))?;
+        if channel.counterparty(from) != Some(hop.id) {
+            anyhow::bail!(
+                "Channel `{}` does not connect `{from}` to `{}`",
+                hop.channel,
+                hop.id
+            );
+        }
+        if channel.capacity > Msat::ZERO && hop.amount_msat > channel.capacity {
+            anyhow::bail!(
+                "Hop amount `{}` exceeds the capacity `{}` of channel `{}`",
+                hop.amount_msat,
+                channel.capacity,
+                hop.channel
+            );
+        }
+
+        let policy = channel.policy_from(from);
+        if let Some(policy) = policy {
+            if !policy.can_forward(hop.amount_msat) {
+                anyhow::bail!(
+                    "Channel `{}` can not forward `{}` from `{from}`",
+                    hop.channel,
+                    hop.amount_msat
+                );
+            }
+        }
+
+        // The previous hop pays the fee and the CLTV delta of the node
+        // forwarding through this channel, except ourselves.
+        if i > 0 {
+            let Some(policy) = policy else {
+                anyhow::bail!("Unknown policy of `{from}` for channel `{}`", hop.channel);
+            };
+            let previous = &part.path[i - 1];
+            let required_msat = hop
+                .amount_msat
+                .saturating_add(policy.fee_msat(hop.amount_msat));
+            if previous.amount_msat < required_msat {
+                anyhow::bail!(
+                    "Hop amount `{}` to `{from}` does not cover the fee to forward `{}` through channel `{}`, `{required_msat}` are required",
+                    previous.amount_msat,
+                    hop.amount_msat,
+                    hop.channel
+                );
+            }
+            let required_delay = (hop.delay as u64).saturating_add(policy.delay);
+            if (previous.delay as u64) < required_delay {
+                anyhow::bail!(
+                    "Hop delay `{}` to `{from}` does not cover the CLTV delta of channel `{}`, `{required_delay}` is required",
+                    previous.delay,
+                    hop.channel
+                );
+            }
+        }
+        from = &hop.id;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::dijkstra::Dijkstra;
+    use crate::algorithms::test_utils::{node, route_input, scid, TestGraph};
+    use crate::strategy::Strategy;
+
+    fn graph() -> TestGraph {
+        let mut graph = TestGraph::new();
+        graph.add_channel("1x1x0", "alice", "bob", 1_000_000, (6, 0, 0), (6, 0, 0));
+        graph.add_channel("2x1x0", "bob", "carol", 1_000_000, (6, 1000, 10), (6, 0, 0));
+        graph
+    }
+
+    #[test]
+    fn test_validate_route() {
+        let input = route_input(graph(), "alice", "carol", 100_000, 18);
+        let output = Dijkstra::new().route(&input).unwrap();
+        let graph = input.graph.as_ref();
+        let alice = node("alice");
+        let amount_msat = Msat::new(100_000);
+        validate_route(graph, &alice, &output, amount_msat, 18).unwrap();
+
+        // The final CLTV is too small
+        assert!(validate_route(graph, &alice, &output, amount_msat, 20).is_err());
+
+        // The parts do not deliver the whole amount
+        assert!(validate_route(graph, &alice, &output, Msat::new(200_000), 18).is_err());
+
+        // bob is not paid enough to forward
+        let mut part = output.parts[0].clone();
+        part.path[0].amount_msat -= Msat::new(1);
+        assert!(validate_part(graph, &alice, &part, 18).is_err());
+
+        // bob does not get enough time to forward
+        let mut part = output.parts[0].clone();
+        part.path[0].delay = part.path[1].delay;
+        assert!(validate_part(graph, &alice, &part, 18).is_err());
+
+        // The channel does not reach carol
+        let mut part = output.parts[0].clone();
+        part.path[1].channel = scid("1x1x0");
+        assert!(validate_part(graph, &alice, &part, 18).is_err());
+
+        // The amount does not fit the channel
+        let mut part = output.parts[0].clone();
+        part.path[1].amount_msat = Msat::new(2_000_000);
+        part.amount_msat = Msat::new(2_000_000);
+        assert!(validate_part(graph, &alice, &part, 18).is_err());
+    }
+}
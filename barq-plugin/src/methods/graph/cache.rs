@@ -0,0 +1,103 @@
+//! Network graphs cached in the plugin state
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use clightningrpc_plugin::error;
+use clightningrpc_plugin::errors::PluginError;
+
+use barq_common::graph::NetworkGraph;
+
+use crate::methods::graph::cln::build_cln_network_graph;
+use crate::methods::graph::gossip_store::GossipStoreReader;
+use crate::methods::graph::p2p::gossip_store_path;
+use crate::plugin::State;
+
+/// Snapshots of the network graphs shared by the RPC methods
+///
+/// The graphs are never modified in place: a refresh builds a new graph and
+/// swaps it in, so a payment keeps routing on the snapshot it started with.
+#[derive(Default)]
+pub struct GraphCache {
+    /// The graph built from `listchannels`
+    cln: RwLock<Option<Arc<dyn NetworkGraph>>>,
+    /// The graph built from the gossip map
+    p2p: RwLock<Option<Arc<dyn NetworkGraph>>>,
+    /// The reader keeping the graph built from the gossip map up to date
+    gossip_store: Mutex<Option<GossipStoreReader>>,
+}
+
+impl GraphCache {
+    /// Get the cached graph, with peer-to-peer information or not, loading it
+    /// if it is not cached yet.
+    pub fn get(&self, state: &State, p2p: bool) -> Result<Arc<dyn NetworkGraph>, PluginError> {
+        let cached = self.slot(p2p).read().unwrap_or_else(|err| err.into_inner());
+        if let Some(graph) = cached.as_ref() {
+            return Ok(graph.clone());
+        }
+        drop(cached);
+        self.load(state, p2p)
+    }
+
+    /// Load the graph again and replace the cached one
+    ///
+    /// The graph built from the gossip map is updated with the records
+    /// appended to the `gossip_store` since the last load. If the update
+    /// fails, the cached graph is kept.
+    pub fn load(&self, state: &State, p2p: bool) -> Result<Arc<dyn NetworkGraph>, PluginError> {
+        if !p2p {
+            let graph: Arc<dyn NetworkGraph> = Arc::new(build_cln_network_graph(state)?);
+            let mut cached = self.cln.write().unwrap_or_else(|err| err.into_inner());
+            *cached = Some(graph.clone());
+            return Ok(graph);
+        }
+
+        // Only the reader is locked during the update: the payments keep
+        // routing on the cached snapshot, that the reader copies before
+        // changing the graph.
+        let mut reader = self
+            .gossip_store
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if reader.is_none() {
+            *reader = Some(GossipStoreReader::new(&gossip_store_path(state)?));
+        }
+        // SAFETY: the reader was just created.
+        let graph: Arc<dyn NetworkGraph> = reader
+            .as_mut()
+            .unwrap()
+            .update()
+            .map_err(|err| error!("Error reading gossip store: {err}"))?;
+        let mut cached = self.p2p.write().unwrap_or_else(|err| err.into_inner());
+        *cached = Some(graph.clone());
+        Ok(graph)
+    }
+
+    /// Load both graphs again, logging the failures
+    pub fn refresh(&self, state: &State) {
+        for p2p in [false, true] {
+            if let Err(err) = self.load(state, p2p) {
+                log::warn!("unable to refresh the network graph (p2p: {p2p}): {err:?}");
+            }
+        }
+    }
+
+    fn slot(&self, p2p: bool) -> &RwLock<Option<Arc<dyn NetworkGraph>>> {
+        if p2p {
+            &self.p2p
+        } else {
+            &self.cln
+        }
+    }
+}
+
+/// Load the graphs of `state` now and refresh them every `interval` on a
+/// background thread
+pub fn spawn_refresh(state: State, interval: Duration) {
+    thread::spawn(move || loop {
+        state.graphs.refresh(&state);
+        log::debug!("network graphs refreshed, next refresh in {interval:?}");
+        thread::sleep(interval);
+    });
+}
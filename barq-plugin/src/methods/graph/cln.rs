@@ -4,15 +4,18 @@ use serde::{Deserialize, Serialize};
 
 use clightningrpc_plugin::errors::PluginError;
 
-use barq_common::graph::{Channel, NetworkGraph, Node};
+use barq_common::amount::Msat;
+use barq_common::graph::{Channel, ChannelPolicy, NetworkGraph, Node};
+use barq_common::node_id::NodeId;
+use barq_common::short_channel_id::ShortChannelId;
 
 use crate::plugin::State;
 
 /// CLN Network Graph
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CLNNetworkGraph {
-    nodes: HashMap<String, Node>,
-    channels: HashMap<String, Channel>,
+    nodes: HashMap<NodeId, Node>,
+    channels: HashMap<ShortChannelId, Channel>,
 }
 
 impl Default for CLNNetworkGraph {
@@ -32,17 +35,25 @@ impl CLNNetworkGraph {
 
     /// Adds a node to the network graph.
     fn add_node(&mut self, node: Node) {
-        self.nodes.insert(node.id.clone(), node);
+        self.nodes.insert(node.id, node);
     }
 
     /// Adds a channel to the network graph.
-    pub fn add_channel(&mut self, channel: Channel) {
+    ///
+    /// If the channel is already known, the policies of `channel` are merged
+    /// into the known ones, so both directions are kept.
+    pub fn add_channel(&mut self, mut channel: Channel) {
+        if let Some(known) = self.channels.get(&channel.short_channel_id) {
+            let mut known = known.clone();
+            known.merge(&channel);
+            channel = known;
+        }
         self.channels
-            .insert(channel.short_channel_id.clone(), channel.clone());
+            .insert(channel.short_channel_id, channel.clone());
         if let Some(node1) = self.nodes.get_mut(&channel.node1) {
             node1.add_channel(&channel);
         } else {
-            let mut new_node = Node::new(&channel.node1);
+            let mut new_node = Node::new(channel.node1);
             new_node.add_channel(&channel);
             self.add_node(new_node);
         }
@@ -50,7 +61,7 @@ impl CLNNetworkGraph {
         if let Some(node2) = self.nodes.get_mut(&channel.node2) {
             node2.add_channel(&channel);
         } else {
-            let mut new_node = Node::new(&channel.node2);
+            let mut new_node = Node::new(channel.node2);
             new_node.add_channel(&channel);
             self.add_node(new_node);
         }
@@ -58,14 +69,32 @@ impl CLNNetworkGraph {
 }
 
 impl NetworkGraph for CLNNetworkGraph {
-    fn get_node(&self, id: &str) -> Option<&Node> {
+    fn get_node(&self, id: &NodeId) -> Option<&Node> {
         self.nodes.get(id)
     }
 
-    fn get_channel(&self, id: &str) -> Option<&Channel> {
+    fn get_channel(&self, id: &ShortChannelId) -> Option<&Channel> {
         self.channels.get(id)
     }
 
+    fn remove_channel(&mut self, id: &ShortChannelId) -> Option<Channel> {
+        let channel = self.channels.remove(id)?;
+        for node_id in [&channel.node1, &channel.node2] {
+            if let Some(node) = self.nodes.get_mut(node_id) {
+                node.remove_channel(id);
+            }
+        }
+        Some(channel)
+    }
+
+    fn remove_node(&mut self, id: &NodeId) -> Option<Node> {
+        let node = self.nodes.remove(id)?;
+        for channel in &node.channels {
+            self.remove_channel(&channel.short_channel_id);
+        }
+        Some(node)
+    }
+
     fn get_nodes(&self) -> Vec<&Node> {
         self.nodes.values().collect()
     }
@@ -87,16 +116,24 @@ struct CLNListChannelsResponse {
     channels: Vec<ChannelInfo>,
 }
 
-/// Structure representing a channel as returned by CLN `listchannels` method.
+/// Structure representing one direction of a channel as returned by CLN
+/// `listchannels` method.
 #[derive(Deserialize, Debug)]
 struct ChannelInfo {
-    source: String,
-    destination: String,
-    short_channel_id: String,
-    amount_msat: u64,
+    source: NodeId,
+    destination: NodeId,
+    short_channel_id: ShortChannelId,
+    /// 0 if `source` is the lexicographically lesser node id, 1 otherwise
+    direction: u8,
+    amount_msat: Msat,
+    active: bool,
+    last_update: u64,
     delay: u64,
-    base_fee_millisatoshi: u64,
+    base_fee_millisatoshi: Msat,
     fee_per_millionth: u64,
+    htlc_minimum_msat: Msat,
+    #[serde(default)]
+    htlc_maximum_msat: Option<Msat>,
 }
 
 /// Function to build the network graph using the plugin state.
@@ -108,21 +145,29 @@ pub fn build_cln_network_graph(state: &State) -> Result<CLNNetworkGraph, PluginE
 
     let mut graph = CLNNetworkGraph::new();
 
-    // Iterate over the channels to construct the nodes and edges
+    // Iterate over the channels to construct the nodes and edges, each
+    // direction of a channel is listed on its own.
     for channel in response.channels {
-        // Convert amount_msat to u64
-        let amount_msat = channel.amount_msat;
-
-        // Add channel to the graph
-        let edge = Channel::new(
-            &channel.short_channel_id,
-            &channel.source,
-            &channel.destination,
-            amount_msat,
-            channel.delay,
-            channel.base_fee_millisatoshi,
-            channel.fee_per_millionth,
+        let (node1, node2) = if channel.direction == 0 {
+            (channel.source, channel.destination)
+        } else {
+            (channel.destination, channel.source)
+        };
+        let mut edge = Channel::new(channel.short_channel_id, node1, node2, channel.amount_msat);
+        edge.set_policy(
+            channel.direction,
+            ChannelPolicy {
+                delay: channel.delay,
+                base_fee_millisatoshi: channel.base_fee_millisatoshi,
+                fee_per_millionth: channel.fee_per_millionth,
+                htlc_minimum_msat: channel.htlc_minimum_msat,
+                htlc_maximum_msat: channel.htlc_maximum_msat,
+                disabled: !channel.active,
+                last_update: channel.last_update,
+                channel_update: None,
+            },
         );
+        // Add channel to the graph
         graph.add_channel(edge);
     }
 
@@ -0,0 +1,406 @@
+//! Incremental reader of the CLN `gossip_store` file
+//!
+//! CLN appends every gossip message it accepts to the `gossip_store`, so we
+//! remember how far we read and only apply the records appended since then.
+//! When CLN compacts the store, it writes a new file, renames it over the old
+//! one and appends a `gossip_store_ended` record to the old one: in that case
+//! we start again from the beginning of the new file.
+//!
+//! See: https://github.com/ElementsProject/lightning/blob/master/common/gossip_store.h
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use barq_common::amount::{Msat, Sat};
+use barq_common::graph::{
+    Channel, ChannelPolicy, NetworkGraph, Node, CHANNEL_FLAG_DIRECTION, CHANNEL_FLAG_DISABLED,
+};
+use barq_common::node_id::NodeId;
+use barq_common::short_channel_id::ShortChannelId;
+
+use crate::methods::graph::p2p::P2PNetworkGraph;
+
+/// Mask of the major version in the version byte of the store
+const MAJOR_VERSION_MASK: u8 = 0xE0;
+/// Oldest minor version with the `flags`/`len` record header we parse
+const MIN_MINOR_VERSION: u8 = 12;
+
+/// Size of the header of each record: flags, len, crc and timestamp
+const RECORD_HEADER_LEN: usize = 12;
+/// The record was superseded by a later one
+const FLAG_DELETED: u16 = 0x8000;
+/// The channel of the record is being closed
+const FLAG_DYING: u16 = 0x0800;
+
+const MSG_CHANNEL_ANNOUNCEMENT: u16 = 256;
+const MSG_NODE_ANNOUNCEMENT: u16 = 257;
+const MSG_CHANNEL_UPDATE: u16 = 258;
+/// Capacity (in satoshis) of the channel announced by the previous record
+const MSG_CHANNEL_AMOUNT: u16 = 4101;
+/// The channel was closed
+const MSG_DELETE_CHAN: u16 = 4103;
+/// The store was replaced by a new file
+const MSG_ENDED: u16 = 4105;
+/// The funding output of the channel was spent
+const MSG_CHAN_DYING: u16 = 4106;
+
+/// Length of the four signatures starting a `channel_announcement`
+const CHANNEL_ANNOUNCEMENT_SIGNATURES_LEN: usize = 4 * 64;
+/// Length of the fields of a `channel_update` before the short channel id:
+/// the signature and the chain hash
+const CHANNEL_UPDATE_SCID_OFFSET: usize = 64 + 32;
+
+/// Reader of the `gossip_store` keeping a graph up to date
+pub struct GossipStoreReader {
+    path: PathBuf,
+    /// The store we are reading, with its inode to detect when it is replaced
+    file: Option<(File, u64)>,
+    /// Offset of the first record not applied yet
+    offset: u64,
+    /// The last announced channel, waiting for its capacity
+    last_announced: Option<ShortChannelId>,
+    graph: Arc<P2PNetworkGraph>,
+}
+
+impl GossipStoreReader {
+    /// Create a reader of the store at `path`, with an empty graph
+    pub fn new(path: &Path) -> Self {
+        GossipStoreReader {
+            path: path.to_path_buf(),
+            file: None,
+            offset: 0,
+            last_announced: None,
+            graph: Arc::new(P2PNetworkGraph::new()),
+        }
+    }
+
+    /// Apply the records appended since the last update, and return a
+    /// snapshot of the graph
+    ///
+    /// The graph is updated in place, unless someone still holds a previous
+    /// snapshot of it.
+    pub fn update(&mut self) -> Result<Arc<P2PNetworkGraph>> {
+        if self.replaced()? {
+            log::info!(
+                "gossip store {:?} was replaced, reading it again",
+                self.path
+            );
+            self.reset();
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        // SAFETY: the file was just opened.
+        let (file, _) = self.file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+
+        let mut position = 0;
+        let mut ended = false;
+        while let Some((flags, msg)) = next_record(&buffer[position..]) {
+            position += RECORD_HEADER_LEN + msg.len();
+            if flags & FLAG_DELETED != 0 {
+                continue;
+            }
+            match self.apply(flags, msg) {
+                Ok(true) => {
+                    ended = true;
+                    break;
+                }
+                Ok(false) => {}
+                Err(err) => log::debug!("skipping gossip store record: {err}"),
+            }
+        }
+        self.offset += position as u64;
+
+        if ended {
+            log::info!("gossip store {:?} ended, reading the new one", self.path);
+            self.reset();
+            return self.update();
+        }
+        Ok(self.graph.clone())
+    }
+
+    /// Apply a single record to the graph, returning `true` if it tells that
+    /// the store was replaced
+    fn apply(&mut self, flags: u16, msg: &[u8]) -> Result<bool> {
+        let msg_type = read_u16(msg, 0)?;
+        // The raw messages are kept without their type, the way LDK reads them
+        let body = &msg[2..];
+        match msg_type {
+            MSG_CHANNEL_ANNOUNCEMENT => {
+                // The features have a variable length, the chain hash follows
+                let features_len = read_u16(body, CHANNEL_ANNOUNCEMENT_SIGNATURES_LEN)? as usize;
+                let offset = CHANNEL_ANNOUNCEMENT_SIGNATURES_LEN + 2 + features_len + 32;
+                let scid = ShortChannelId::from(read_u64(body, offset)?);
+                // The two node ids follow the short channel id
+                let node1 = NodeId::try_from(read_bytes(body, offset + 8, 33)?)?;
+                let node2 = NodeId::try_from(read_bytes(body, offset + 41, 33)?)?;
+                if flags & FLAG_DYING != 0 {
+                    self.last_announced = None;
+                    return Ok(false);
+                }
+                let mut channel = Channel::new(scid, node1, node2, Msat::ZERO);
+                channel.channel_announcement = Some(body.to_vec());
+                Arc::make_mut(&mut self.graph).add_channel(channel);
+                self.last_announced = Some(scid);
+            }
+            MSG_CHANNEL_AMOUNT => {
+                let capacity = Sat::new(read_u64(body, 0)?);
+                if let Some(scid) = self.last_announced.take() {
+                    Arc::make_mut(&mut self.graph).set_capacity(&scid, capacity.into());
+                }
+            }
+            MSG_CHANNEL_UPDATE => {
+                let scid = ShortChannelId::from(read_u64(body, CHANNEL_UPDATE_SCID_OFFSET)?);
+                let offset = CHANNEL_UPDATE_SCID_OFFSET + 8;
+                let channel_flags = read_bytes(body, offset + 5, 1)?[0];
+                let policy = ChannelPolicy {
+                    delay: read_u16(body, offset + 6)? as u64,
+                    base_fee_millisatoshi: Msat::new(read_u32(body, offset + 16)? as u64),
+                    fee_per_millionth: read_u32(body, offset + 20)? as u64,
+                    htlc_minimum_msat: Msat::new(read_u64(body, offset + 8)?),
+                    htlc_maximum_msat: Some(Msat::new(read_u64(body, offset + 24)?)),
+                    disabled: channel_flags & CHANNEL_FLAG_DISABLED != 0,
+                    last_update: read_u32(body, offset)? as u64,
+                    channel_update: Some(body.to_vec()),
+                };
+                Arc::make_mut(&mut self.graph).set_policy(
+                    &scid,
+                    channel_flags & CHANNEL_FLAG_DIRECTION,
+                    policy,
+                );
+            }
+            MSG_NODE_ANNOUNCEMENT => {
+                // The features have a variable length
+                let features_len = read_u16(body, 64)? as usize;
+                let offset = 64 + 2 + features_len + 4;
+                let mut node = Node::new(NodeId::try_from(read_bytes(body, offset, 33)?)?);
+                let alias = read_bytes(body, offset + 33 + 3, 32)?;
+                let alias = String::from_utf8_lossy(alias);
+                let alias = alias.trim_end_matches('\0');
+                if !alias.is_empty() {
+                    node.set_alias(alias);
+                }
+                node.node_announcement = Some(body.to_vec());
+                Arc::make_mut(&mut self.graph).add_node_announcement(node);
+            }
+            MSG_DELETE_CHAN | MSG_CHAN_DYING => {
+                let scid = ShortChannelId::from(read_u64(body, 0)?);
+                Arc::make_mut(&mut self.graph).remove_channel(&scid);
+            }
+            MSG_ENDED => return Ok(true),
+            // Private channels and other local records are not in the graph
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    /// Open the store, checking its version
+    fn open(&mut self) -> Result<()> {
+        let mut file = File::open(&self.path)?;
+        let inode = file.metadata()?.ino();
+        let mut version = [0; 1];
+        file.read_exact(&mut version)?;
+        let version = version[0];
+        if version & MAJOR_VERSION_MASK != 0 || version < MIN_MINOR_VERSION {
+            anyhow::bail!("Unsupported gossip store version `{version}`");
+        }
+        self.file = Some((file, inode));
+        self.offset = 1;
+        Ok(())
+    }
+
+    /// Whether the file at `path` is not the one we are reading anymore
+    fn replaced(&self) -> Result<bool> {
+        let Some((file, inode)) = self.file.as_ref() else {
+            return Ok(false);
+        };
+        let metadata = std::fs::metadata(&self.path)?;
+        Ok(metadata.ino() != *inode || file.metadata()?.len() < self.offset)
+    }
+
+    /// Forget everything we read, to read the store again from the beginning
+    fn reset(&mut self) {
+        self.file = None;
+        self.offset = 0;
+        self.last_announced = None;
+        self.graph = Arc::new(P2PNetworkGraph::new());
+    }
+}
+
+/// Split the next complete record from `buffer` into its flags and message
+///
+/// Returns `None` if the record is not fully written yet.
+fn next_record(buffer: &[u8]) -> Option<(u16, &[u8])> {
+    if buffer.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+    let flags = u16::from_be_bytes([buffer[0], buffer[1]]);
+    let len = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+    let msg = buffer.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)?;
+    Some((flags, msg))
+}
+
+fn read_bytes(msg: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    msg.get(offset..offset + len)
+        .ok_or_else(|| anyhow::anyhow!("Gossip message too short ({} bytes)", msg.len()))
+}
+
+fn read_u16(msg: &[u8], offset: usize) -> Result<u16> {
+    // SAFETY: `read_bytes` returns exactly the requested length.
+    Ok(u16::from_be_bytes(
+        read_bytes(msg, offset, 2)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u32(msg: &[u8], offset: usize) -> Result<u32> {
+    // SAFETY: `read_bytes` returns exactly the requested length.
+    Ok(u32::from_be_bytes(
+        read_bytes(msg, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(msg: &[u8], offset: usize) -> Result<u64> {
+    // SAFETY: `read_bytes` returns exactly the requested length.
+    Ok(u64::from_be_bytes(
+        read_bytes(msg, offset, 8)?.try_into().unwrap(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::*;
+
+    const ALICE: &str = "022d223620a359a47ff7f7ac447c85c46c923da53389221a0054c11c1e3ca31d59";
+    const BOB: &str = "035d2b1192dfba134e10e540875d366ebc8bc353d5aa766b80c090b39c3a5d885d";
+    const CAROL: &str = "0382ce59ebf18be7d84677c2e35f23294b9992ceca95491fcf8a56c6cb2d9de199";
+
+    fn record(msg_type: u16, body: &[u8]) -> Vec<u8> {
+        let mut msg = msg_type.to_be_bytes().to_vec();
+        msg.extend_from_slice(body);
+        let mut record = 0u16.to_be_bytes().to_vec();
+        record.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+        record.extend_from_slice(&[0; 8]);
+        record.extend_from_slice(&msg);
+        record
+    }
+
+    fn channel_announcement(scid: u64, node1: &str, node2: &str) -> Vec<u8> {
+        let mut body = vec![0; CHANNEL_ANNOUNCEMENT_SIGNATURES_LEN];
+        body.extend_from_slice(&0u16.to_be_bytes());
+        body.extend_from_slice(&[0; 32]);
+        body.extend_from_slice(&scid.to_be_bytes());
+        body.extend_from_slice(node1.parse::<NodeId>().unwrap().as_bytes());
+        body.extend_from_slice(node2.parse::<NodeId>().unwrap().as_bytes());
+        body.extend_from_slice(&[0; 66]);
+        record(MSG_CHANNEL_ANNOUNCEMENT, &body)
+    }
+
+    fn channel_update(scid: u64, direction: u8, timestamp: u32, base_fee: u32) -> Vec<u8> {
+        let mut body = vec![0; CHANNEL_UPDATE_SCID_OFFSET];
+        body.extend_from_slice(&scid.to_be_bytes());
+        body.extend_from_slice(&timestamp.to_be_bytes());
+        body.extend_from_slice(&[1, direction]);
+        body.extend_from_slice(&6u16.to_be_bytes());
+        body.extend_from_slice(&0u64.to_be_bytes());
+        body.extend_from_slice(&base_fee.to_be_bytes());
+        body.extend_from_slice(&10u32.to_be_bytes());
+        body.extend_from_slice(&u64::MAX.to_be_bytes());
+        record(MSG_CHANNEL_UPDATE, &body)
+    }
+
+    fn write_store(path: &Path, records: &[Vec<u8>]) {
+        let mut file = File::create(path).unwrap();
+        file.write_all(&[MIN_MINOR_VERSION]).unwrap();
+        for record in records {
+            file.write_all(record).unwrap();
+        }
+    }
+
+    fn append(path: &Path, records: &[Vec<u8>]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        for record in records {
+            file.write_all(record).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_tail_gossip_store() {
+        let dir = std::env::temp_dir().join(format!("barq-gossip-store-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("gossip_store");
+        let scid = (103 << 40) | (1 << 16);
+        write_store(
+            &path,
+            &[
+                channel_announcement(scid, ALICE, BOB),
+                record(MSG_CHANNEL_AMOUNT, &1_000u64.to_be_bytes()),
+                channel_update(scid, 0, 1, 1000),
+            ],
+        );
+
+        let mut reader = GossipStoreReader::new(&path);
+        let graph = reader.update().unwrap();
+        let channel = graph.get_channel(&ShortChannelId::from(scid)).unwrap();
+        assert_eq!(channel.capacity, Msat::new(1_000_000));
+        assert_eq!(
+            channel.node1_policy.as_ref().unwrap().base_fee_millisatoshi,
+            Msat::new(1000)
+        );
+        assert!(channel.node2_policy.is_none());
+
+        // Only the appended records are applied, even if half written
+        let update = channel_update(scid, 1, 2, 2000);
+        append(&path, &[update[..10].to_vec()]);
+        assert!(reader
+            .update()
+            .unwrap()
+            .get_channel(&ShortChannelId::from(scid))
+            .unwrap()
+            .node2_policy
+            .is_none());
+        append(&path, &[update[10..].to_vec()]);
+        let graph = reader.update().unwrap();
+        let channel = graph.get_channel(&ShortChannelId::from(scid)).unwrap();
+        assert_eq!(
+            channel.node2_policy.as_ref().unwrap().base_fee_millisatoshi,
+            Msat::new(2000)
+        );
+        assert_eq!(
+            graph
+                .get_node(&ALICE.parse().unwrap())
+                .unwrap()
+                .channels
+                .len(),
+            1
+        );
+
+        append(&path, &[record(MSG_DELETE_CHAN, &scid.to_be_bytes())]);
+        assert!(reader
+            .update()
+            .unwrap()
+            .get_channel(&ShortChannelId::from(scid))
+            .is_none());
+
+        // A compacted store replaces the old one
+        let compacted = dir.join("gossip_store.tmp");
+        write_store(&compacted, &[channel_announcement(scid + 1, ALICE, CAROL)]);
+        std::fs::rename(&compacted, &path).unwrap();
+        let graph = reader.update().unwrap();
+        assert!(graph.get_channel(&ShortChannelId::from(scid + 1)).is_some());
+        assert_eq!(graph.get_channels().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+//! Our own channels, from CLN `listpeerchannels`
+
+use serde::Deserialize;
+
+use clightningrpc_plugin::errors::PluginError;
+
+use barq_common::amount::Msat;
+use barq_common::local_channels::{LocalChannel, LocalChannels};
+use barq_common::node_id::NodeId;
+use barq_common::short_channel_id::ShortChannelId;
+
+use crate::plugin::State;
+
+/// Response from `listpeerchannels` RPC command of Core Lightning
+///
+/// See: https://docs.corelightning.org/reference/lightning-listpeerchannels#return-value
+#[derive(Deserialize, Debug)]
+struct ListPeerChannelsResponse {
+    channels: Vec<PeerChannel>,
+}
+
+/// One of our channels, as returned by `listpeerchannels`
+///
+/// The balances are not known for every state of the channel.
+#[derive(Deserialize, Debug)]
+struct PeerChannel {
+    peer_id: NodeId,
+    peer_connected: bool,
+    state: String,
+    short_channel_id: Option<ShortChannelId>,
+    total_msat: Option<Msat>,
+    spendable_msat: Option<Msat>,
+    receivable_msat: Option<Msat>,
+    minimum_htlc_out_msat: Option<Msat>,
+    private: Option<bool>,
+}
+
+impl PeerChannel {
+    /// The channel, if it can be routed through
+    fn into_local_channel(self) -> Option<LocalChannel> {
+        Some(LocalChannel {
+            short_channel_id: self.short_channel_id?,
+            peer_id: self.peer_id,
+            peer_connected: self.peer_connected,
+            state: self.state,
+            total_msat: self.total_msat.unwrap_or_default(),
+            spendable_msat: self.spendable_msat.unwrap_or_default(),
+            receivable_msat: self.receivable_msat.unwrap_or_default(),
+            minimum_htlc_out_msat: self.minimum_htlc_out_msat.unwrap_or_default(),
+            private: self.private.unwrap_or_default(),
+        })
+    }
+}
+
+/// Fetch our channels, with the balance we can spend on each.
+pub fn list_local_channels(state: &State) -> Result<LocalChannels, PluginError> {
+    let response: ListPeerChannelsResponse = state
+        .call("listpeerchannels", serde_json::json!({}))
+        .map_err(|err| PluginError::new(err.code, &err.message, err.data))?;
+    Ok(response
+        .channels
+        .into_iter()
+        .filter_map(PeerChannel::into_local_channel)
+        .collect())
+}
@@ -1,2 +1,5 @@
+pub mod cache;
 pub mod cln;
+pub mod gossip_store;
+pub mod local;
 pub mod p2p;
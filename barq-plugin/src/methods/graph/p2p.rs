@@ -1,21 +1,24 @@
 #![allow(unused)]
 
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 
-use clightningrpc_gossip_map::GossipMap;
 use clightningrpc_plugin::error;
 use clightningrpc_plugin::errors::PluginError;
 
-use barq_common::graph::{Channel, NetworkGraph, Node};
+use barq_common::amount::Msat;
+use barq_common::graph::{Channel, ChannelPolicy, NetworkGraph, Node};
+use barq_common::node_id::NodeId;
+use barq_common::short_channel_id::ShortChannelId;
 
 use crate::plugin::State;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct P2PNetworkGraph {
-    nodes: HashMap<String, Node>,
-    channels: HashMap<String, Channel>,
+    nodes: HashMap<NodeId, Node>,
+    channels: HashMap<ShortChannelId, Channel>,
 }
 
 impl Default for P2PNetworkGraph {
@@ -35,33 +38,93 @@ impl P2PNetworkGraph {
 
     /// Adds a node to the network graph.
     pub fn add_node(&mut self, node: Node) {
-        self.nodes.insert(node.id.clone(), node);
+        self.nodes.insert(node.id, node);
+    }
+
+    /// Adds the announcement of a node to the network graph, keeping the
+    /// channels already known for it.
+    pub fn add_node_announcement(&mut self, node: Node) {
+        match self.nodes.get_mut(&node.id) {
+            Some(known) => {
+                known.alias = node.alias;
+                known.node_announcement = node.node_announcement;
+            }
+            None => self.add_node(node),
+        }
     }
 
     /// Adds a channel to the network graph.
-    pub fn add_channel(&mut self, channel: Channel) {
+    ///
+    /// If the channel is already known, the policies of `channel` are merged
+    /// into the known ones, so both directions are kept.
+    pub fn add_channel(&mut self, mut channel: Channel) {
+        if let Some(known) = self.channels.get(&channel.short_channel_id) {
+            let mut known = known.clone();
+            known.merge(&channel);
+            channel = known;
+        }
         self.channels
-            .insert(channel.short_channel_id.clone(), channel.clone());
-        self.nodes
-            .get_mut(&channel.node1)
-            .unwrap_or(&mut Node::new(&channel.node1))
-            .add_channel(&channel);
-        self.nodes
-            .get_mut(&channel.node2)
-            .unwrap_or(&mut Node::new(&channel.node1))
-            .add_channel(&channel);
+            .insert(channel.short_channel_id, channel.clone());
+        for node_id in [channel.node1, channel.node2] {
+            self.nodes
+                .entry(node_id)
+                .or_insert_with(|| Node::new(node_id))
+                .add_channel(&channel);
+        }
+    }
+
+    /// Sets the capacity of a known channel.
+    pub fn set_capacity(&mut self, id: &ShortChannelId, capacity: Msat) {
+        self.update_channel(id, |channel| channel.set_capacity(capacity));
+    }
+
+    /// Sets the policy of one direction of a known channel.
+    pub fn set_policy(&mut self, id: &ShortChannelId, direction: u8, policy: ChannelPolicy) {
+        self.update_channel(id, |channel| channel.set_policy(direction, policy));
+    }
+
+    /// Applies `update` to a known channel, and to the copies of its nodes.
+    fn update_channel<F: FnOnce(&mut Channel)>(&mut self, id: &ShortChannelId, update: F) {
+        let Some(channel) = self.channels.get_mut(id) else {
+            return;
+        };
+        update(channel);
+        let channel = channel.clone();
+        for node_id in [&channel.node1, &channel.node2] {
+            if let Some(node) = self.nodes.get_mut(node_id) {
+                node.add_channel(&channel);
+            }
+        }
     }
 }
 
 impl NetworkGraph for P2PNetworkGraph {
-    fn get_node(&self, id: &str) -> Option<&Node> {
+    fn get_node(&self, id: &NodeId) -> Option<&Node> {
         self.nodes.get(id)
     }
 
-    fn get_channel(&self, id: &str) -> Option<&Channel> {
+    fn get_channel(&self, id: &ShortChannelId) -> Option<&Channel> {
         self.channels.get(id)
     }
 
+    fn remove_channel(&mut self, id: &ShortChannelId) -> Option<Channel> {
+        let channel = self.channels.remove(id)?;
+        for node_id in [&channel.node1, &channel.node2] {
+            if let Some(node) = self.nodes.get_mut(node_id) {
+                node.remove_channel(id);
+            }
+        }
+        Some(channel)
+    }
+
+    fn remove_node(&mut self, id: &NodeId) -> Option<Node> {
+        let node = self.nodes.remove(id)?;
+        for channel in &node.channels {
+            self.remove_channel(&channel.short_channel_id);
+        }
+        Some(node)
+    }
+
     fn get_nodes(&self) -> Vec<&Node> {
         self.nodes.values().collect()
     }
@@ -75,18 +138,11 @@ impl NetworkGraph for P2PNetworkGraph {
     }
 }
 
-/// Function to build the network graph using the plugin state.
-pub fn build_p2p_network_graph(state: &State) -> Result<P2PNetworkGraph, PluginError> {
-    let mut graph = P2PNetworkGraph::new();
-    // Get the gossip map path from the plugin state
-    // FIXME: Currently, we are loading the gossip map from the file system
-    //        each time the `barqpay` method is called. This is not efficient.
-    //        We should load the gossip map once and cache it in the plugin state.
-    //        See: https://github.com/tareknaser/barq/issues/21 for more details.
-
+/// Path of the `gossip_store` of the CLN node the plugin is attached to.
+pub fn gossip_store_path(state: &State) -> Result<PathBuf, PluginError> {
     // SAFETY: It is safe to unwrap here because the plugin init the path always.
     let lightning_rpc_path = state.cln_rpc_path.as_ref().unwrap();
-    let lightning_rpc_path = std::path::Path::new(&lightning_rpc_path);
+    let lightning_rpc_path = Path::new(&lightning_rpc_path);
     // Lightning path is /home/user/.lightning
     let lightning_path = lightning_rpc_path.parent().ok_or_else(|| {
         error!(
@@ -94,19 +150,5 @@ pub fn build_p2p_network_graph(state: &State) -> Result<P2PNetworkGraph, PluginE
             lightning_rpc_path
         )
     })?;
-    // SAFETY: It is safe to unwrap here because the plugin init the network always.
-    let gossip_map_path = lightning_path.join("gossip_store");
-    let gossip_map_path = gossip_map_path.to_str().ok_or_else(|| {
-        error!(
-            "Failed to convert gossip map path to string: {:?}",
-            gossip_map_path
-        )
-    })?;
-    let gossip_map = GossipMap::from_file(gossip_map_path)
-        .map_err(|err| error!("Error reading gossip map from file: {err}"))?;
-
-    for channel in gossip_map.channels.values() {
-        graph.add_channel(channel.clone().into())
-    }
-    Ok(graph)
+    Ok(lightning_path.join("gossip_store"))
 }
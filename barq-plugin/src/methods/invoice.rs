@@ -0,0 +1,272 @@
+//! The invoices and offers `barqpay` pays, decoded by CLN `decode`
+
+use std::collections::VecDeque;
+
+use serde::Deserialize;
+use serde_json as json;
+
+use clightningrpc_plugin::error;
+use clightningrpc_plugin::errors::PluginError;
+
+use barq_common::amount::Msat;
+use barq_common::blinded::BlindedPath;
+use barq_common::keysend::{Keysend, KEYSEND_FINAL_CLTV};
+use barq_common::node_id::NodeId;
+use barq_common::route_hints::RouteHint;
+use barq_common::Network;
+
+use crate::plugin::State;
+
+/// Response from `decode` RPC command of Core Lightning, for the strings
+/// `barqpay` accepts
+///
+/// See: https://docs.corelightning.org/reference/lightning-decode#return-value
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+enum Decoded {
+    #[serde(rename = "bolt11 invoice")]
+    Bolt11(Bolt11),
+    #[serde(rename = "bolt12 invoice")]
+    Bolt12Invoice(Bolt12Invoice),
+    #[serde(rename = "bolt12 offer")]
+    Bolt12Offer(Bolt12Offer),
+}
+
+#[derive(Deserialize, Debug)]
+struct Bolt11 {
+    /// The BIP173 name for the currency
+    currency: String,
+    payee: NodeId,
+    amount_msat: Option<Msat>,
+    payment_hash: String,
+    min_final_cltv_expiry: u64,
+    payment_secret: Option<String>,
+    /// The private channels to reach the payee
+    #[serde(default)]
+    routes: Vec<RouteHint>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Bolt12Invoice {
+    invoice_node_id: NodeId,
+    invoice_amount_msat: Msat,
+    invoice_payment_hash: String,
+    invoice_paths: Vec<BlindedPath>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Bolt12Offer {
+    /// The amount in `offer_currency`, or in msat if there is none. Missing
+    /// when the offer lets the payer choose it
+    offer_amount: Option<u64>,
+}
+
+/// Response from `fetchinvoice` RPC command of Core Lightning
+///
+/// See: https://docs.corelightning.org/reference/lightning-fetchinvoice#return-value
+#[derive(Deserialize, Debug)]
+struct FetchInvoiceResponse {
+    invoice: String,
+}
+
+/// What `barqpay` or `barqkeysend` has to pay, whatever the kind of invoice
+#[derive(Debug)]
+pub struct Invoice {
+    pub payment_hash: String,
+    /// Only BOLT11 invoices have one, the blinded paths carry their own
+    pub payment_secret: Option<String>,
+    pub payee: NodeId,
+    pub amount_msat: Msat,
+    /// The CLTV delta the payee requires
+    pub min_final_cltv_expiry: u64,
+    pub route_hints: Vec<RouteHint>,
+    /// The blinded path the payment goes through to reach the payee
+    pub blinded_path: Option<BlindedPath>,
+    /// The other blinded paths of the invoice, by increasing fee, to try when
+    /// no route reaches the introduction node of `blinded_path`
+    pub fallback_paths: VecDeque<BlindedPath>,
+    /// The preimage and the custom records of a keysend, that has no invoice
+    pub keysend: Option<Keysend>,
+}
+
+impl Invoice {
+    /// A keysend of `amount_msat` to `payee`
+    pub fn keysend(payee: NodeId, amount_msat: Msat, keysend: Keysend) -> Self {
+        Invoice {
+            payment_hash: keysend.payment_hash(),
+            payment_secret: None,
+            payee,
+            amount_msat,
+            min_final_cltv_expiry: KEYSEND_FINAL_CLTV,
+            route_hints: vec![],
+            blinded_path: None,
+            fallback_paths: VecDeque::new(),
+            keysend: Some(keysend),
+        }
+    }
+
+    /// Pay through the next blinded path of the invoice
+    ///
+    /// Returns whether there was one left.
+    pub fn next_blinded_path(&mut self) -> bool {
+        let Some(path) = self.fallback_paths.pop_front() else {
+            return false;
+        };
+        self.min_final_cltv_expiry = path.payinfo.cltv_expiry_delta;
+        self.blinded_path = Some(path);
+        true
+    }
+
+    /// The node the routes must reach: the payee, or the introduction node of
+    /// its blinded path
+    pub fn dest_pubkey(&self) -> NodeId {
+        self.blinded_path
+            .as_ref()
+            .and_then(|path| path.first_node_id)
+            .unwrap_or(self.payee)
+    }
+
+    /// The CLTV delta the routes must deliver to `dest_pubkey`
+    pub fn cltv(&self) -> u64 {
+        match self.blinded_path.as_ref() {
+            Some(path) => path.payinfo.cltv_expiry_delta,
+            None => self.min_final_cltv_expiry,
+        }
+    }
+
+    /// The amount to route to `dest_pubkey` to deliver `amount_msat` to the
+    /// payee
+    pub fn amount_to_route(&self, amount_msat: Msat) -> Msat {
+        match self.blinded_path.as_ref() {
+            Some(path) => amount_msat.saturating_add(path.payinfo.fee_msat(amount_msat)),
+            None => amount_msat,
+        }
+    }
+
+    /// The amount the payee gets from a part delivering `amount_msat` to
+    /// `dest_pubkey`
+    ///
+    /// Each part pays the base fee of the blinded path, so the parts of a
+    /// split payment deliver a bit less than the amount routed.
+    pub fn amount_delivered(&self, amount_msat: Msat) -> Msat {
+        match self.blinded_path.as_ref() {
+            Some(path) => path.payinfo.amount_delivered(amount_msat),
+            None => amount_msat,
+        }
+    }
+}
+
+/// Decode the BOLT11 invoice, BOLT12 invoice or BOLT12 offer to pay
+///
+/// The invoice of an offer is requested with `fetchinvoice`. `amount_msat`
+/// is only accepted when the invoice or the offer does not set one.
+pub fn decode_invoice(
+    state: &State,
+    invoice: &str,
+    amount_msat: Option<Msat>,
+    network: Network,
+) -> Result<Invoice, PluginError> {
+    match decode(state, invoice)? {
+        Decoded::Bolt11(b11) => {
+            // Get the network of the invoice
+            // See: https://github.com/lightning/bolts/blob/master/11-payment-encoding.md#human-readable-part
+            let invoice_network = match b11.currency.as_str() {
+                "bc" => Network::Bitcoin,
+                "tb" => Network::Testnet,
+                "tbs" => Network::Signet,
+                "bcrt" => Network::Regtest,
+                _ => return Err(error!("Unknown currency: {}", b11.currency)),
+            };
+            if invoice_network != network {
+                return Err(error!(
+                    "Invoice network ({}) does not match node network ({})",
+                    invoice_network, network
+                ));
+            }
+            Ok(Invoice {
+                payment_hash: b11.payment_hash,
+                payment_secret: b11.payment_secret,
+                payee: b11.payee,
+                amount_msat: resolve_amount(b11.amount_msat, amount_msat)?,
+                min_final_cltv_expiry: b11.min_final_cltv_expiry,
+                route_hints: b11.routes,
+                blinded_path: None,
+                fallback_paths: VecDeque::new(),
+                keysend: None,
+            })
+        }
+        // CLN refuses to decode the BOLT12 strings of another chain
+        Decoded::Bolt12Invoice(b12) => bolt12_invoice(b12, amount_msat),
+        Decoded::Bolt12Offer(offer) => {
+            // The amount of the offer may be in another currency, only
+            // whether it has one matters here
+            resolve_amount(offer.offer_amount.map(Msat::new), amount_msat)?;
+            let response: FetchInvoiceResponse = state
+                .call(
+                    "fetchinvoice",
+                    serde_json::json!({
+                        "offer": invoice,
+                        "amount_msat": amount_msat,
+                    }),
+                )
+                .map_err(|err| PluginError::new(err.code, &err.message, err.data))?;
+            match decode(state, &response.invoice)? {
+                // The invoice has the amount we asked for
+                Decoded::Bolt12Invoice(b12) => bolt12_invoice(b12, None),
+                _ => Err(error!("`fetchinvoice` did not return a BOLT12 invoice")),
+            }
+        }
+    }
+}
+
+fn decode(state: &State, invoice: &str) -> Result<Decoded, PluginError> {
+    let decoded: json::Value = state
+        .call(
+            "decode",
+            serde_json::json!({
+                "string": invoice,
+            }),
+        )
+        .map_err(|err| PluginError::new(err.code, &err.message, err.data))?;
+    if decoded["valid"] != json::Value::Bool(true) {
+        return Err(error!("Invalid {}: {decoded}", decoded["type"]));
+    }
+    json::from_value(decoded).map_err(|err| error!("Unsupported invoice: {err}"))
+}
+
+/// Pay the BOLT12 invoice through the blinded path with the lowest fee, and
+/// keep the other ones in case it can not be reached
+fn bolt12_invoice(b12: Bolt12Invoice, amount_msat: Option<Msat>) -> Result<Invoice, PluginError> {
+    let amount_msat = resolve_amount(Some(b12.invoice_amount_msat), amount_msat)?;
+    // We can only route to an introduction node we know by its id
+    let mut paths = b12
+        .invoice_paths
+        .into_iter()
+        .filter(|path| path.first_node_id.is_some() && !path.path.is_empty())
+        .collect::<Vec<_>>();
+    paths.sort_by_key(|path| path.payinfo.fee_msat(amount_msat));
+    let mut fallback_paths = VecDeque::from(paths);
+    let blinded_path = fallback_paths
+        .pop_front()
+        .ok_or_else(|| error!("The invoice has no blinded path starting with a node id"))?;
+    Ok(Invoice {
+        payment_hash: b12.invoice_payment_hash,
+        payment_secret: None,
+        payee: b12.invoice_node_id,
+        amount_msat,
+        min_final_cltv_expiry: blinded_path.payinfo.cltv_expiry_delta,
+        route_hints: vec![],
+        blinded_path: Some(blinded_path),
+        fallback_paths,
+        keysend: None,
+    })
+}
+
+/// The amount to pay, from the invoice or else from the request
+fn resolve_amount(invoice: Option<Msat>, request: Option<Msat>) -> Result<Msat, PluginError> {
+    match (invoice, request) {
+        (Some(_), Some(_)) => Err(error!("barqpay execution failed: amount_msat not required")),
+        (Some(amount), None) | (None, Some(amount)) => Ok(amount),
+        (None, None) => Err(error!("barqpay execution failed: amount_msat is required")),
+    }
+}
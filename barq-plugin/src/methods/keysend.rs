@@ -0,0 +1,117 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json as json;
+
+use clightningrpc_plugin::error;
+use clightningrpc_plugin::errors::PluginError;
+use clightningrpc_plugin::plugin::Plugin;
+
+use barq_common::amount::Msat;
+use barq_common::keysend::Keysend;
+use barq_common::node_id::NodeId;
+use barq_common::registry::DEFAULT_STRATEGY;
+use barq_common::Network;
+
+use crate::methods::invoice::Invoice;
+use crate::methods::pay::{pay_invoice, BarqPayResponse};
+use crate::methods::router::Router;
+use crate::methods::utils::{get_node_info, ConstraintsRequest};
+use crate::plugin::State;
+
+/// Request payload for Barq keysend RPC method, named after the parameters of
+/// CLN `keysend`
+///
+/// See: https://docs.corelightning.org/reference/lightning-keysend
+#[derive(Deserialize, Serialize)]
+pub struct BarqKeysendRequest {
+    pub destination: NodeId,
+    pub amount_msat: Msat,
+    /// The hex encoded custom records to send to the destination, by TLV type
+    #[serde(default)]
+    pub extratlvs: BTreeMap<u64, String>,
+    /// The strategy to use for routing the payment
+    #[serde(default)]
+    pub strategy: Option<String>,
+    /// Whether to use the rapid gossip sync map to build the network graph
+    #[serde(default)]
+    pub use_rapid_gossip_sync: bool,
+    /// Constraints the route must honour
+    #[serde(flatten)]
+    pub constraints: ConstraintsRequest,
+    /// Maximum number of routes to try before giving up
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+    /// Time budget (in seconds) for starting new attempts
+    #[serde(default)]
+    pub retry_for: Option<u64>,
+}
+
+impl BarqKeysendRequest {
+    /// The name of the strategy to use for routing the payment
+    pub fn strategy(&self) -> &str {
+        self.strategy.as_deref().unwrap_or(DEFAULT_STRATEGY)
+    }
+}
+
+/// Response payload for Barq keysend RPC method
+#[derive(Deserialize, Serialize)]
+pub struct BarqKeysendResponse {
+    pub payment_hash: String,
+    /// The preimage we picked, that proves the payment
+    pub payment_preimage: String,
+    #[serde(flatten)]
+    pub payment: BarqPayResponse,
+}
+
+/// Barq RPC method to send a keysend payment
+///
+/// The payment goes through the same strategies and retries as `barqpay`,
+/// with the preimage we picked in the onion.
+pub fn barq_keysend(
+    plugin: &mut Plugin<State>,
+    request: json::Value,
+) -> Result<json::Value, PluginError> {
+    log::info!("barqkeysend called with request: {}", request);
+    let request: BarqKeysendRequest = json::from_value(request).map_err(|err| error!("{err}"))?;
+
+    let state = &plugin.state;
+    let node_info = get_node_info(state)?;
+    let node_network = Network::from_str(&node_info.network).map_err(|e| error!("{e}"))?;
+
+    let keysend = Keysend::new(&request.extratlvs)
+        .map_err(|err| error!("barqkeysend execution failed: {err}"))?;
+    let payment_preimage = keysend.payment_preimage();
+    let mut invoice = Invoice::keysend(request.destination, request.amount_msat, keysend);
+
+    // The payee can not tell the parts of a keysend belong together
+    let mut constraints = request.constraints.constraints()?;
+    constraints.max_parts = Some(1);
+    let router = Router::new(
+        state,
+        request.strategy(),
+        node_info.id,
+        invoice.dest_pubkey(),
+        node_network,
+        invoice.cltv(),
+        request.use_rapid_gossip_sync,
+    )?
+    .with_constraints(constraints);
+
+    let payment = pay_invoice(
+        state,
+        "barqkeysend",
+        &node_info,
+        router,
+        &mut invoice,
+        request.max_attempts,
+        request.retry_for,
+    )?;
+    let response = BarqKeysendResponse {
+        payment_hash: invoice.payment_hash,
+        payment_preimage,
+        payment,
+    };
+    Ok(json::to_value(response)?)
+}
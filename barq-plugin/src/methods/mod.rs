@@ -1,5 +1,10 @@
 //! Barq Routing RPC methods
 
 pub mod graph;
+pub mod invoice;
+pub mod keysend;
 pub mod pay;
 pub mod route_info;
+pub mod router;
+pub mod strategies;
+pub mod utils;
@@ -1,22 +1,51 @@
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use barq_common::algorithms::direct::Direct;
-use barq_common::algorithms::probabilistic::LDKRoutingStrategy;
 use serde::{Deserialize, Serialize};
 use serde_json as json;
 
+use clightningrpc_common::errors::RpcError;
 use clightningrpc_plugin::error;
 use clightningrpc_plugin::errors::PluginError;
 use clightningrpc_plugin::plugin::Plugin;
 
-use barq_common::graph::NetworkGraph;
-use barq_common::strategy::{RouteInput, Strategy, StrategyKind};
+use barq_common::amount::Msat;
+use barq_common::blinded::onion_hops;
+use barq_common::inflight::PartId;
+use barq_common::node_id::NodeId;
+use barq_common::registry::DEFAULT_STRATEGY;
+use barq_common::short_channel_id::ShortChannelId;
+use barq_common::strategy::{PaymentFailure, PaymentOutcome, RoutePart};
 use barq_common::Network;
 
-use crate::methods::graph::cln::build_cln_network_graph;
-use crate::methods::graph::p2p::build_p2p_network_graph;
+use crate::methods::invoice::{decode_invoice, Invoice};
+use crate::methods::router::{Router, SkippedStrategy};
+use crate::methods::utils::{get_node_info, ConstraintsRequest, NodeInfo};
 use crate::plugin::State;
 
+/// Default number of routes we try before giving up on a payment
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+/// Default time budget (in seconds) for retrying a payment, same as CLN `pay`
+const DEFAULT_RETRY_FOR: u64 = 60;
+
+/// `sendpay`/`waitsendpay` error code for an onion the next hop could not
+/// parse
+///
+/// See: https://docs.corelightning.org/reference/lightning-waitsendpay#errors
+const PAY_UNPARSEABLE_ONION: i32 = 202;
+/// `sendpay`/`waitsendpay` error code for a failure that is worth retrying on
+/// a different route
+const PAY_TRY_OTHER_ROUTE: i32 = 204;
+
+/// BOLT4 failure code flag telling that the whole node is failing, not only
+/// the channel
+///
+/// See: https://github.com/lightning/bolts/blob/master/04-onion-routing.md#failure-messages
+const BOLT4_NODE_FLAG: u16 = 0x2000;
+
 /// Response from `sendpay` RPC command of Core Lightning
 ///
 /// See: https://docs.corelightning.org/reference/lightning-sendpay#return-value
@@ -27,10 +56,13 @@ pub struct CLNSendpayResponse {
     payment_hash: String,
     groupid: u64,
     destination: String,
-    amount_msat: u64,
-    amount_sent_msat: u64,
+    amount_msat: Msat,
+    amount_sent_msat: Msat,
     created_at: u64,
     status: Status,
+    /// Only returned by `waitsendpay` once the payment is complete
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payment_preimage: Option<String>,
 }
 
 /// Status of the payment
@@ -45,9 +77,11 @@ enum Status {
 // serde(default) sets the default value to None if no input is given.
 #[derive(Deserialize, Serialize)]
 pub struct BarqPayRequest {
-    pub bolt11_invoice: String,
+    /// The BOLT11 invoice, BOLT12 invoice or BOLT12 offer to pay
+    #[serde(alias = "bolt11_invoice")]
+    pub invoice: String,
     #[serde(default)]
-    pub amount_msat: Option<u64>,
+    pub amount_msat: Option<Msat>,
     /// The strategy to use for routing the payment
     #[serde(default)]
     pub strategy: Option<String>,
@@ -57,14 +91,21 @@ pub struct BarqPayRequest {
     /// graph
     #[serde(default)]
     pub use_rapid_gossip_sync: bool,
+    /// Constraints the route must honour
+    #[serde(flatten)]
+    pub constraints: ConstraintsRequest,
+    /// Maximum number of routes to try before giving up
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+    /// Time budget (in seconds) for starting new attempts
+    #[serde(default)]
+    pub retry_for: Option<u64>,
 }
 
 impl BarqPayRequest {
-    pub fn strategy(&self) -> anyhow::Result<StrategyKind> {
-        if let Some(ref s) = self.strategy {
-            return StrategyKind::from_str(s);
-        }
-        Ok(StrategyKind::default())
+    /// The name of the strategy to use for routing the payment
+    pub fn strategy(&self) -> &str {
+        self.strategy.as_deref().unwrap_or(DEFAULT_STRATEGY)
     }
 }
 
@@ -74,32 +115,79 @@ pub struct BarqPayResponse {
     pub status: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub message: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub response: Option<CLNSendpayResponse>,
+    /// The `waitsendpay` result of every part that completed the payment
+    pub parts: Vec<CLNSendpayResponse>,
+    /// Number of times the strategy was asked for a route
+    pub attempts: u32,
+    /// The strategy that produced the last route
+    pub strategy: String,
+    /// The strategies that did not produce a route, and why
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub skipped: Vec<SkippedStrategy>,
 }
 
-/// Response from `decodepay` RPC command of Core Lightning
+/// Single entry of the `listsendpays` RPC command of Core Lightning
 ///
-/// See: https://docs.corelightning.org/reference/lightning-decodepay#return-value
+/// See: https://docs.corelightning.org/reference/lightning-listsendpays#return-value
 #[derive(Deserialize, Debug)]
-struct Bolt11 {
-    /// The BIP173 name for the currency
-    currency: String,
-    payee: String,
-    amount_msat: Option<u64>,
-    payment_hash: String,
-    min_final_cltv_expiry: u64,
-    payment_secret: Option<String>,
+struct CLNSendpay {
+    groupid: u64,
+}
+
+/// Response from `listsendpays` RPC command of Core Lightning
+#[derive(Deserialize, Debug)]
+struct CLNListSendpaysResponse {
+    payments: Vec<CLNSendpay>,
 }
 
-/// Response from `getinfo` RPC command of Core Lightning
+/// Failure details attached to `sendpay`/`waitsendpay` errors
 ///
-/// See: https://docs.corelightning.org/reference/lightning-getinfo#return-value
-#[derive(Debug, Deserialize)]
-pub struct NodeInfo {
-    pub id: String,
-    /// Represents the type of network on the node are working
-    pub network: String,
+/// See: https://docs.corelightning.org/reference/lightning-waitsendpay#errors
+#[derive(Deserialize, Debug, Default)]
+struct SendpayFailure {
+    erring_index: Option<u64>,
+    failcode: Option<u16>,
+    failcodename: Option<String>,
+    erring_node: Option<NodeId>,
+    erring_channel: Option<ShortChannelId>,
+}
+
+impl SendpayFailure {
+    /// Extract the failure details from the RPC error, if any
+    fn from_rpc_error(err: &RpcError) -> Self {
+        err.data
+            .clone()
+            .and_then(|data| json::from_value(data).ok())
+            .unwrap_or_default()
+    }
+
+    /// Whether the failure is blamed on the whole node rather than on a
+    /// channel
+    fn is_node_failure(&self) -> bool {
+        self.failcode
+            .map(|code| code & BOLT4_NODE_FLAG != 0)
+            .unwrap_or_default()
+    }
+}
+
+impl From<SendpayFailure> for PaymentFailure {
+    fn from(failure: SendpayFailure) -> Self {
+        PaymentFailure {
+            erring_index: failure.erring_index,
+            erring_node: failure.erring_node,
+            erring_channel: failure.erring_channel,
+            failcode: failure.failcode,
+        }
+    }
+}
+
+/// Response from `createonion` RPC command of Core Lightning
+///
+/// See: https://docs.corelightning.org/reference/lightning-createonion#return-value
+#[derive(Deserialize, Debug)]
+struct CLNCreateOnionResponse {
+    onion: String,
+    shared_secrets: Vec<String>,
 }
 
 /// Barq RPC method to execute a payment
@@ -111,106 +199,342 @@ pub fn barq_pay(
     let request: BarqPayRequest = json::from_value(request).map_err(|err| error!("{err}"))?;
 
     let state = &plugin.state;
-    // FIXME: the decodepay is deprecated, we should use `decode`.
-    let b11: Bolt11 = state
+    let node_info = get_node_info(state)?;
+    let node_network = Network::from_str(&node_info.network).map_err(|e| error!("{e}"))?;
+
+    let mut invoice = decode_invoice(state, &request.invoice, request.amount_msat, node_network)?;
+
+    let router = Router::new(
+        state,
+        request.strategy(),
+        node_info.id,
+        invoice.dest_pubkey(),
+        node_network,
+        invoice.cltv(),
+        request.use_rapid_gossip_sync,
+    )?
+    .with_constraints(request.constraints.constraints()?)
+    .with_route_hints(invoice.route_hints.clone());
+
+    let response = pay_invoice(
+        state,
+        "barqpay",
+        &node_info,
+        router,
+        &mut invoice,
+        request.max_attempts,
+        request.retry_for,
+    )?;
+    Ok(json::to_value(response)?)
+}
+
+/// Pay `invoice` with the routes of `router`, until it is paid or the
+/// attempts run out
+///
+/// The parts are sent in the same group, and every failed part is routed
+/// again, through the next blinded path of `invoice` if the current one can
+/// not be reached. `rpc_name` is the RPC method the payment comes from, for the
+/// errors.
+pub fn pay_invoice(
+    state: &State,
+    rpc_name: &str,
+    node_info: &NodeInfo,
+    mut router: Router,
+    invoice: &mut Invoice,
+    max_attempts: Option<u32>,
+    retry_for: Option<u64>,
+) -> Result<BarqPayResponse, PluginError> {
+    let amount = invoice.amount_msat;
+
+    // All the parts of this payment belong to the same group, that must be
+    // different from the ones of previous `barqpay` calls for this invoice.
+    let previous: CLNListSendpaysResponse = state
         .call(
-            "decodepay",
+            "listsendpays",
             serde_json::json!({
-                "bolt11": request.bolt11_invoice
+                "payment_hash": invoice.payment_hash,
             }),
         )
         .map_err(|err| PluginError::new(err.code, &err.message, err.data))?;
+    let groupid = previous
+        .payments
+        .iter()
+        .map(|payment| payment.groupid + 1)
+        .max()
+        .unwrap_or_default();
 
-    // Get the network of the invoice
-    // See: https://github.com/lightning/bolts/blob/master/11-payment-encoding.md#human-readable-part
-    let invoice_network = match b11.currency.as_str() {
-        "bc" => Network::Bitcoin,
-        "tb" => Network::Testnet,
-        "tbs" => Network::Signet,
-        "bcrt" => Network::Regtest,
-        _ => return Err(error!("Unknown currency: {}", b11.currency)),
+    // The fee budget of the whole payment, shared by all its parts
+    let max_fee_msat = router.constraints().max_fee_for(amount);
+    let max_attempts = max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS);
+    let retry_for = Duration::from_secs(retry_for.unwrap_or(DEFAULT_RETRY_FOR));
+    let started_at = Instant::now();
+    let mut attempts = 0;
+
+    // The invoice changes when we fall back to another blinded path
+    let payment_hash = invoice.payment_hash.clone();
+    let part_id = |partid| PartId {
+        payment_hash: payment_hash.clone(),
+        groupid,
+        partid,
     };
 
-    let node_info: NodeInfo = state
-        .call("getinfo", serde_json::json!({}))
-        .map_err(|err| PluginError::new(err.code, &err.message, err.data))?;
+    let (sender, receiver) = mpsc::channel();
+    let mut next_partid = 1;
+    // The strategy that routed each part, the part and the amount it delivers
+    let mut inflight: HashMap<u64, (String, RoutePart, Msat)> = HashMap::new();
+    let mut completed = Vec::new();
+    let mut delivered_msat = Msat::ZERO;
+    // The fee paid by the delivered parts
+    let mut spent_fee_msat = Msat::ZERO;
+    let mut last_error: Option<RpcError> = None;
+    let mut stop_routing = false;
+    let mut strategy = String::new();
+    loop {
+        // Route the amount that is neither delivered nor in flight yet
+        let inflight_msat: Msat = inflight.values().map(|(_, _, amount)| *amount).sum();
+        let remaining_msat = amount.saturating_sub(delivered_msat.saturating_add(inflight_msat));
+        if remaining_msat > Msat::ZERO && !stop_routing {
+            attempts += 1;
+            if let Some(max_fee_msat) = max_fee_msat {
+                let inflight_fee_msat: Msat =
+                    inflight.values().map(|(_, part, _)| part.fee_msat()).sum();
+                router.set_max_fee(
+                    max_fee_msat.saturating_sub(spent_fee_msat.saturating_add(inflight_fee_msat)),
+                );
+            }
+            // Execute the routing process
+            let output = match router.route(invoice.amount_to_route(remaining_msat)) {
+                Ok(output) => output,
+                // The introduction node of the blinded path may be out of
+                // reach, the next one may not
+                Err(err) if invoice.next_blinded_path() => {
+                    log::info!(
+                        "unable to route to the blinded path: {err:?}, trying the next one from `{}`",
+                        invoice.dest_pubkey()
+                    );
+                    router.set_destination(invoice.dest_pubkey(), invoice.cltv());
+                    continue;
+                }
+                Err(err) if inflight.is_empty() => return Err(err),
+                Err(err) => {
+                    log::warn!("unable to route the remaining {remaining_msat}: {err:?}");
+                    stop_routing = true;
+                    continue;
+                }
+            };
+            // SAFETY: the router has a current strategy after finding a route.
+            strategy = router.strategy().unwrap().to_string();
+
+            for part in output.parts {
+                let part_msat = invoice.amount_delivered(part.amount_msat);
+                if part_msat == Msat::ZERO {
+                    log::info!(
+                        "skipping the part of {}, too small to pay the blinded path",
+                        part.amount_msat
+                    );
+                    continue;
+                }
+                log::info!(
+                    "part {next_partid} of {} selected by the strategy is: `{:?}`",
+                    part.amount_msat,
+                    part.path
+                );
+                let partid = next_partid;
+                next_partid += 1;
+                if let Err(err) = send_part(state, invoice, &part, part_msat, partid, groupid) {
+                    router.report(&strategy, &part, &payment_outcome(Some(&err)));
+                    if !learn_from_failure(&mut router, &invoice.payee, &err) {
+                        stop_routing = true;
+                    }
+                    last_error = Some(err);
+                    continue;
+                }
+                // The part uses the liquidity of its channels until resolved,
+                // for this payment and the concurrent ones.
+                state
+                    .inflight
+                    .add(part_id(partid), node_info.id, part.clone());
+
+                // Wait for the result of each part on its own thread, so a
+                // failed part can be routed again while the others are in
+                // flight.
+                let state = state.clone();
+                let sender = sender.clone();
+                let payment_hash = invoice.payment_hash.clone();
+                thread::spawn(move || {
+                    let result = state.call::<_, CLNSendpayResponse>(
+                        "waitsendpay",
+                        serde_json::json!({
+                            "payment_hash": payment_hash,
+                            "partid": partid,
+                            "groupid": groupid,
+                        }),
+                    );
+                    // The receiver is gone only if `barqpay` already returned
+                    let _ = sender.send((partid, result));
+                });
+                inflight.insert(partid, (strategy.clone(), part, part_msat));
+            }
 
-    let amount = match (b11.amount_msat, request.amount_msat) {
-        (Some(_), Some(_)) => {
-            return Err(error!("barqpay execution failed: amount_msat not required"))
+            if attempts >= max_attempts || started_at.elapsed() >= retry_for {
+                stop_routing = true;
+            }
+            continue;
         }
-        (Some(amount), None) | (None, Some(amount)) => amount,
-        (None, None) => return Err(error!("barqpay execution failed: amount_msat not required")),
-    };
 
-    let node_network = node_info.network;
-    let node_network = Network::from_str(&node_network).map_err(|e| error!("{e}"))?;
+        if inflight.is_empty() {
+            if delivered_msat == amount {
+                // Construct the response from the output
+                return Ok(BarqPayResponse {
+                    status: "success".to_string(),
+                    message: None,
+                    parts: completed,
+                    attempts,
+                    strategy,
+                    skipped: router.skipped(),
+                });
+            }
+            return Err(match last_error {
+                Some(err) => error!(
+                    "{rpc_name} failed after {attempts} attempts: {}",
+                    err.message
+                ),
+                None => error!("{rpc_name} failed after {attempts} attempts"),
+            });
+        }
 
-    if invoice_network != node_network {
-        return Err(error!(
-            "Invoice network ({}) does not match node network ({})",
-            invoice_network, node_network
-        ));
+        // SAFETY: there is always a thread waiting for each in-flight part, and
+        // we keep a sender alive.
+        let (partid, result) = receiver.recv().expect("waitsendpay thread disappeared");
+        // SAFETY: the thread sends back a partid we inserted.
+        let (routed_by, part, part_msat) = inflight.remove(&partid).expect("unknown part");
+        state.inflight.remove(&part_id(partid));
+        router.report(&routed_by, &part, &payment_outcome(result.as_ref().err()));
+        match result {
+            Ok(response) => {
+                delivered_msat += part_msat;
+                spent_fee_msat += part.fee_msat();
+                completed.push(response);
+            }
+            Err(err) => {
+                if !learn_from_failure(&mut router, &invoice.payee, &err) {
+                    stop_routing = true;
+                }
+                last_error = Some(err);
+            }
+        }
     }
+}
 
-    let strategy = request.strategy().map_err(|e| error!("{e}"))?;
-    // If the probabilistic strategy is selected, build the network graph from the
-    // gossip map. Else, build the network graph from the plugin state
-    let network_graph: Box<dyn NetworkGraph> = match strategy {
-        StrategyKind::Direct => Box::new(build_cln_network_graph(state)?),
-        StrategyKind::Probabilistic => Box::new(build_p2p_network_graph(state)?),
-    };
+/// Send `part`, delivering `amount_msat` to the payee
+///
+/// `sendpay` can not build the onion of a blinded path or of a keysend, so we
+/// build it and send it with `sendonion`. Either way, `waitsendpay` waits for
+/// the result.
+fn send_part(
+    state: &State,
+    invoice: &Invoice,
+    part: &RoutePart,
+    amount_msat: Msat,
+    partid: u64,
+    groupid: u64,
+) -> Result<CLNSendpayResponse, RpcError> {
+    let hops = if let Some(blinded_path) = invoice.blinded_path.as_ref() {
+        onion_hops(
+            &part.path,
+            blinded_path,
+            amount_msat,
+            invoice.amount_msat,
+            base_expiry(state)?,
+        )
+    } else if let Some(keysend) = invoice.keysend.as_ref() {
+        keysend.onion_hops(&part.path, base_expiry(state)?)
+    } else {
+        return state.call(
+            "sendpay",
+            serde_json::json!({
+                "route": part.path,
+                "payment_hash": invoice.payment_hash,
+                "payment_secret": invoice.payment_secret,
+                "amount_msat": invoice.amount_msat,
+                "partid": partid,
+                "groupid": groupid,
+            }),
+        );
+    }
+    .map_err(|err| RpcError {
+        code: -1,
+        message: format!("{err}"),
+        data: None,
+    })?;
+    let onion: CLNCreateOnionResponse = state.call(
+        "createonion",
+        serde_json::json!({
+            "hops": hops,
+            "assocdata": invoice.payment_hash,
+        }),
+    )?;
+    state.call(
+        "sendonion",
+        serde_json::json!({
+            "onion": onion.onion,
+            // SAFETY: `onion_hops` fails on an empty route.
+            "first_hop": part.path.first().unwrap(),
+            "payment_hash": invoice.payment_hash,
+            "shared_secrets": onion.shared_secrets,
+            "partid": partid,
+            "groupid": groupid,
+            "destination": invoice.payee,
+            "amount_msat": invoice.amount_msat,
+        }),
+    )
+}
 
-    let input = RouteInput {
-        src_pubkey: node_info.id.clone(),
-        dest_pubkey: b11.payee.clone(),
-        network: node_network,
-        amount_msat: amount,
-        cltv: b11.min_final_cltv_expiry,
-        graph: network_graph,
-        use_rapid_gossip_sync: request.use_rapid_gossip_sync,
-    };
+/// The block height the delays of the onion are relative to: the next block,
+/// like CLN does for the first hop
+fn base_expiry(state: &State) -> Result<u64, RpcError> {
+    let node_info: NodeInfo = state.call("getinfo", serde_json::json!({}))?;
+    Ok(node_info.blockheight + 1)
+}
 
-    let strategy: Box<dyn Strategy> = match strategy {
-        StrategyKind::Direct => Box::new(Direct::new()),
-        StrategyKind::Probabilistic => Box::new(LDKRoutingStrategy::new(
-            node_network,
-            plugin.state.cln_rpc_path.clone().unwrap(),
-        )),
-    };
+/// The outcome of a part, from the error of `sendpay` or `waitsendpay` if any
+fn payment_outcome(err: Option<&RpcError>) -> PaymentOutcome {
+    match err {
+        None => PaymentOutcome::Success,
+        Some(err) => PaymentOutcome::Failure(SendpayFailure::from_rpc_error(err).into()),
+    }
+}
 
-    // Execute the routing process
-    let output = strategy.route(&input).map_err(|err| error!("{err}"))?;
-    if output.path.is_empty() {
-        return Err(error!("No route found between us and `{}`", b11.payee));
+/// Learn from a failed part by excluding the erring node or channel from the
+/// next routes
+///
+/// Returns `false` when the failure can not be avoided by routing again.
+fn learn_from_failure(router: &mut Router, dest_pubkey: &NodeId, err: &RpcError) -> bool {
+    if err.code != PAY_TRY_OTHER_ROUTE && err.code != PAY_UNPARSEABLE_ONION {
+        return false;
     }
-    log::info!("path selected by the strategy is: `{:?}`", output.path);
-    let sendpay_request: json::Value = serde_json::json!({
-        "route": output.path,
-        "payment_hash": b11.payment_hash,
-        "payment_secret": b11.payment_secret,
-        "partid": 0,
-    });
-
-    let sendpay_response: CLNSendpayResponse = state
-        .call("sendpay", sendpay_request)
-        .map_err(|err| PluginError::new(err.code, &err.message, err.data))?;
+    let failure = SendpayFailure::from_rpc_error(err);
+    log::info!(
+        "part failed at hop {:?} with `{}`: {:?}",
+        failure.erring_index,
+        failure.failcodename.as_deref().unwrap_or(&err.message),
+        failure
+    );
 
-    let waitsendpay_request: json::Value = serde_json::json!({
-        "payment_hash": sendpay_response.payment_hash.clone(),
-        "partid": 0,
-    });
-
-    let waitsendpay_response: CLNSendpayResponse =
-        state
-            .call("waitsendpay", waitsendpay_request)
-            .map_err(|err| PluginError::new(err.code, &err.message, err.data))?;
-
-    // Construct the response from the output
-    let response = BarqPayResponse {
-        status: "success".to_string(),
-        message: None,
-        response: Some(waitsendpay_response),
-    };
-    Ok(json::to_value(response)?)
+    if failure.erring_node.as_ref() == Some(dest_pubkey) {
+        // The destination gave up on this part (e.g. `mpp_timeout`), there is
+        // nothing to exclude but the amount can be sent again.
+        return err.code == PAY_TRY_OTHER_ROUTE;
+    }
+    if failure.is_node_failure() || err.code == PAY_UNPARSEABLE_ONION {
+        failure
+            .erring_node
+            .as_ref()
+            .is_some_and(|node| router.exclude_node(*node))
+    } else {
+        failure
+            .erring_channel
+            .as_ref()
+            .is_some_and(|channel| router.exclude_channel(*channel))
+    }
 }
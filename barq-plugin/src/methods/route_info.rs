@@ -8,27 +8,40 @@ use clightningrpc_plugin::error;
 use clightningrpc_plugin::errors::PluginError;
 use clightningrpc_plugin::plugin::Plugin;
 
-use barq_common::strategy::{RouteHop, StrategyKind};
+use barq_common::amount::Msat;
+use barq_common::node_id::NodeId;
+use barq_common::registry::DEFAULT_STRATEGY;
+use barq_common::strategy::RoutePart;
+use barq_common::Network;
 
+use crate::methods::router::{Router, SkippedStrategy};
+use crate::methods::utils::{get_node_info, ConstraintsRequest};
 use crate::plugin::State;
 
 /// Request payload for Barq route info RPC method
 #[derive(Deserialize, Serialize)]
 pub struct BarqRouteInfoRequest {
-    pub dest_pubkey: String,
-    pub amount_msat: u64,
+    pub dest_pubkey: NodeId,
+    pub amount_msat: Msat,
     pub cltv: u64,
     /// The strategy to use for routing the payment
     #[serde(skip_serializing_if = "Option::is_none")]
     pub strategy: Option<String>,
+    /// Whether to use the rapid gossip sync map to build the network graph
+    ///
+    /// If false, we will try to use CLN gossip map to build the network
+    /// graph
+    #[serde(default)]
+    pub use_rapid_gossip_sync: bool,
+    /// Constraints the route must honour
+    #[serde(flatten)]
+    pub constraints: ConstraintsRequest,
 }
 
 impl BarqRouteInfoRequest {
-    pub fn strategy(&self) -> anyhow::Result<StrategyKind> {
-        if let Some(ref s) = self.strategy {
-            return StrategyKind::from_str(s);
-        }
-        Ok(StrategyKind::default())
+    /// The name of the strategy to use for routing the payment
+    pub fn strategy(&self) -> &str {
+        self.strategy.as_deref().unwrap_or(DEFAULT_STRATEGY)
     }
 }
 
@@ -38,13 +51,61 @@ pub struct BarqRouteInfoResponse {
     pub status: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub message: Option<String>,
+    /// The parts of the payment, each one with its own path and amount
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub route_info: Option<Vec<RouteHop>>,
+    pub route_info: Option<Vec<RoutePart>>,
+    /// Fee paid to the intermediate hops of all the parts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_fee_msat: Option<Msat>,
+    /// Highest CLTV delay among all the parts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_cltv: Option<u32>,
+    /// Highest number of hops among all the parts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hop_count: Option<usize>,
+    /// The strategy that produced the route
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<String>,
+    /// The strategies that did not produce a route, and why
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub skipped: Vec<SkippedStrategy>,
 }
 
 /// Barq RPC method to get route information
-pub fn barq_route_info(_: &mut Plugin<State>, request: Value) -> Result<Value, PluginError> {
+///
+/// This runs the selected strategy exactly like `barqpay` does, but only
+/// reports the route without sending anything.
+pub fn barq_route_info(plugin: &mut Plugin<State>, request: Value) -> Result<Value, PluginError> {
     log::info!("barqrouteinfo called with request: {}", request);
-    let _: BarqRouteInfoRequest = json::from_value(request).map_err(|err| error!("{err}"))?;
-    Err(error!("Unimplemented"))
+    let request: BarqRouteInfoRequest = json::from_value(request).map_err(|err| error!("{err}"))?;
+
+    let state = &plugin.state;
+    let node_info = get_node_info(state)?;
+    let node_network = Network::from_str(&node_info.network).map_err(|e| error!("{e}"))?;
+
+    let mut router = Router::new(
+        state,
+        request.strategy(),
+        node_info.id,
+        request.dest_pubkey,
+        node_network,
+        request.cltv,
+        request.use_rapid_gossip_sync,
+    )?
+    .with_constraints(request.constraints.constraints()?);
+    let output = router.route(request.amount_msat)?;
+    // SAFETY: the router has a current strategy after finding a route.
+    let strategy = router.strategy().unwrap().to_string();
+
+    let response = BarqRouteInfoResponse {
+        status: "success".to_string(),
+        message: None,
+        total_fee_msat: Some(output.fee_msat()),
+        total_cltv: Some(output.total_delay()),
+        hop_count: output.parts.iter().map(|part| part.path.len()).max(),
+        route_info: Some(output.parts),
+        strategy: Some(strategy),
+        skipped: router.skipped(),
+    };
+    Ok(json::to_value(response)?)
 }
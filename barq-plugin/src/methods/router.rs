@@ -0,0 +1,322 @@
+//! Run the chain of strategies selected by a Barq RPC method
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use clightningrpc_plugin::error;
+use clightningrpc_plugin::errors::PluginError;
+
+use barq_common::amount::Msat;
+use barq_common::node_id::NodeId;
+use barq_common::registry::StrategyEntry;
+use barq_common::route_hints::{HintedGraph, RouteHint};
+use barq_common::short_channel_id::ShortChannelId;
+use barq_common::strategy::{
+    PaymentOutcome, RouteConstraints, RouteInput, RouteOutput, RoutePart, Strategy,
+};
+use barq_common::validation::{validate_amount, validate_route};
+use barq_common::Network;
+
+use crate::methods::graph::local::list_local_channels;
+use crate::methods::utils::{build_network_graph, build_strategy, prune_inflight};
+use crate::plugin::State;
+
+/// A strategy that did not produce the route, and why
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SkippedStrategy {
+    pub strategy: String,
+    pub reason: String,
+}
+
+/// The strategy currently used to route, with the input it routes on
+struct Candidate {
+    name: String,
+    strategy: Box<dyn Strategy>,
+    input: RouteInput,
+}
+
+/// Route a payment with the first strategy of a chain able to do it
+///
+/// A strategy is skipped when its `can_apply` returns false, and the next one
+/// is used as soon as it fails to find a route or finds an invalid one. Every
+/// strategy routes with the same constraints, including the nodes and channels
+/// excluded while paying.
+pub struct Router<'a> {
+    state: &'a State,
+    src_pubkey: NodeId,
+    dest_pubkey: NodeId,
+    network: Network,
+    cltv: u64,
+    use_rapid_gossip_sync: bool,
+    /// The strategies resolved from the one requested, in order
+    strategies: Vec<&'a StrategyEntry>,
+    /// Strategies not tried yet, in order
+    chain: VecDeque<&'a StrategyEntry>,
+    current: Option<Candidate>,
+    /// Strategies we moved away from, that may still have parts in flight
+    retired: Vec<Candidate>,
+    constraints: RouteConstraints,
+    /// The private channels of the invoice, laid over every graph
+    route_hints: Vec<RouteHint>,
+    skipped: Vec<SkippedStrategy>,
+}
+
+impl<'a> Router<'a> {
+    /// Create a router trying the strategies resolved from `strategy`
+    pub fn new(
+        state: &'a State,
+        strategy: &str,
+        src_pubkey: NodeId,
+        dest_pubkey: NodeId,
+        network: Network,
+        cltv: u64,
+        use_rapid_gossip_sync: bool,
+    ) -> Result<Self, PluginError> {
+        let chain = state
+            .registry
+            .resolve(strategy)
+            .map_err(|err| error!("{err}"))?;
+        Ok(Router {
+            state,
+            src_pubkey,
+            dest_pubkey,
+            network,
+            cltv,
+            use_rapid_gossip_sync,
+            chain: chain.iter().copied().collect(),
+            strategies: chain,
+            current: None,
+            retired: vec![],
+            constraints: RouteConstraints::default(),
+            route_hints: vec![],
+            skipped: vec![],
+        })
+    }
+
+    /// Route with the given constraints
+    pub fn with_constraints(mut self, constraints: RouteConstraints) -> Self {
+        self.constraints = constraints;
+        self
+    }
+
+    /// The constraints the routes must honour
+    pub fn constraints(&self) -> &RouteConstraints {
+        &self.constraints
+    }
+
+    /// Cap the total fee of the routes found from now on to `max_fee_msat`
+    ///
+    /// The parts routed again share the fee budget of the payment with the
+    /// parts already sent, so the budget left replaces the fee constraints.
+    pub fn set_max_fee(&mut self, max_fee_msat: Msat) {
+        self.constraints.max_fee_msat = Some(max_fee_msat);
+        self.constraints.max_fee_percent = None;
+        if let Some(candidate) = self.current.as_mut() {
+            candidate.input.constraints.max_fee_msat = Some(max_fee_msat);
+            candidate.input.constraints.max_fee_percent = None;
+        }
+    }
+
+    /// Route to `dest_pubkey` with a final CLTV of `cltv` from now on, trying
+    /// the whole chain of strategies again
+    pub fn set_destination(&mut self, dest_pubkey: NodeId, cltv: u64) {
+        self.dest_pubkey = dest_pubkey;
+        self.cltv = cltv;
+        self.chain = self.strategies.iter().copied().collect();
+        // The parts it routed may still be in flight
+        if let Some(candidate) = self.current.take() {
+            self.retired.push(candidate);
+        }
+    }
+
+    /// Route through the private channels of the invoice too
+    pub fn with_route_hints(mut self, route_hints: Vec<RouteHint>) -> Self {
+        self.route_hints = route_hints;
+        self
+    }
+
+    /// Find a route for `amount_msat`, falling back to the next strategies of
+    /// the chain when the current one can not find it
+    pub fn route(&mut self, amount_msat: Msat) -> Result<RouteOutput, PluginError> {
+        // Our balances change with every part we send, so we read them again
+        let local_channels = Arc::new(list_local_channels(self.state)?);
+        prune_inflight(self.state)?;
+        loop {
+            if self.current.is_none() {
+                let Some(entry) = self.chain.pop_front() else {
+                    return Err(self.exhausted());
+                };
+                self.current = self.candidate(entry)?;
+                continue;
+            }
+
+            // SAFETY: we just checked that there is a current candidate.
+            let candidate = self.current.as_mut().unwrap();
+            candidate.input.amount_msat = amount_msat;
+            candidate.input.local_channels = local_channels.clone();
+            let reason = match candidate.strategy.route(&candidate.input) {
+                Ok(output) if !output.is_empty() => match Self::validate(candidate, &output) {
+                    Ok(()) => return Ok(output),
+                    Err(err) => format!("Invalid route: {err}"),
+                },
+                Ok(_) => format!("No route found between us and `{}`", self.dest_pubkey),
+                Err(err) => format!("{err}"),
+            };
+            log::info!("strategy `{}` failed to route: {reason}", candidate.name);
+            let name = candidate.name.clone();
+            self.skip(&name, reason);
+            // SAFETY: we just checked that there is a current candidate.
+            self.retired.push(self.current.take().unwrap());
+        }
+    }
+
+    /// Name of the strategy that produced the last route
+    pub fn strategy(&self) -> Option<&str> {
+        self.current
+            .as_ref()
+            .map(|candidate| candidate.name.as_str())
+    }
+
+    /// Report the outcome of `part` to the liquidity store and to the
+    /// strategy `name` that routed it
+    pub fn report(&self, name: &str, part: &RoutePart, outcome: &PaymentOutcome) {
+        // SAFETY: the plugin init the liquidity store always.
+        let liquidity = self.state.liquidity.as_ref().unwrap();
+        liquidity.record_payment(&self.src_pubkey, part, outcome);
+        if let Err(err) = liquidity.persist() {
+            log::warn!("unable to persist the liquidity bounds: {err}");
+        }
+
+        let candidate = self
+            .current
+            .iter()
+            .chain(self.retired.iter())
+            .find(|candidate| candidate.name == name);
+        if let Some(candidate) = candidate {
+            candidate.strategy.on_payment_result(part, outcome);
+        }
+    }
+
+    /// Strategies that did not produce a route so far, and why
+    pub fn skipped(self) -> Vec<SkippedStrategy> {
+        self.skipped
+    }
+
+    /// Exclude a node from the routes found from now on
+    ///
+    /// Returns whether the node was not excluded yet. We never exclude
+    /// ourselves.
+    pub fn exclude_node(&mut self, id: NodeId) -> bool {
+        if id == self.src_pubkey || !self.constraints.excluded_nodes.insert(id) {
+            return false;
+        }
+        if let Some(candidate) = self.current.as_mut() {
+            candidate.input.constraints.excluded_nodes.insert(id);
+        }
+        true
+    }
+
+    /// Exclude a channel from the routes found from now on
+    ///
+    /// Returns whether the channel was not excluded yet.
+    pub fn exclude_channel(&mut self, id: ShortChannelId) -> bool {
+        if !self.constraints.excluded_channels.insert(id) {
+            return false;
+        }
+        if let Some(candidate) = self.current.as_mut() {
+            candidate.input.constraints.excluded_channels.insert(id);
+        }
+        true
+    }
+
+    /// Check the route against the constraints and the graph the strategy
+    /// routed on, so a buggy strategy never reaches `sendpay`
+    fn validate(candidate: &Candidate, output: &RouteOutput) -> anyhow::Result<()> {
+        let input = &candidate.input;
+        // The strategies registered by others may not check them
+        input.check_constraints(output)?;
+        if input.use_rapid_gossip_sync {
+            validate_amount(output, input.amount_msat)?;
+            // The route comes from the rapid gossip sync snapshot, that we do
+            // not know
+            log::debug!("skipping the validation of a rapid gossip sync route");
+            return Ok(());
+        }
+        validate_route(
+            input.graph.as_ref(),
+            &input.src_pubkey,
+            output,
+            input.amount_msat,
+            input.cltv,
+        )
+    }
+
+    /// Build the strategy and its input, or `None` if it can not be applied
+    fn candidate(&mut self, entry: &StrategyEntry) -> Result<Option<Candidate>, PluginError> {
+        let mut graph = build_network_graph(self.state, entry)?;
+        if !self.route_hints.is_empty() {
+            graph = Arc::new(HintedGraph::new(
+                graph,
+                &self.route_hints,
+                &self.dest_pubkey,
+            ));
+        }
+        let input = RouteInput {
+            src_pubkey: self.src_pubkey,
+            dest_pubkey: self.dest_pubkey,
+            network: self.network,
+            amount_msat: Msat::ZERO,
+            cltv: self.cltv,
+            graph,
+            use_rapid_gossip_sync: self.use_rapid_gossip_sync,
+            constraints: self.constraints.clone(),
+            // SAFETY: the plugin init the liquidity store always.
+            liquidity: self.state.liquidity.clone().unwrap(),
+            // Read again before each route
+            local_channels: Arc::default(),
+            inflight: self.state.inflight.clone(),
+            route_hints: self.route_hints.clone(),
+        };
+        let strategy = build_strategy(self.state, entry, self.network);
+        match strategy.can_apply(&input) {
+            Ok(true) => Ok(Some(Candidate {
+                name: entry.name.clone(),
+                strategy,
+                input,
+            })),
+            Ok(false) => {
+                self.skip(&entry.name, "The strategy can not be applied".to_string());
+                Ok(None)
+            }
+            Err(err) => {
+                self.skip(&entry.name, format!("{err}"));
+                Ok(None)
+            }
+        }
+    }
+
+    fn skip(&mut self, strategy: &str, reason: String) {
+        self.skipped.push(SkippedStrategy {
+            strategy: strategy.to_string(),
+            reason,
+        });
+    }
+
+    /// The error returned once every strategy of the chain was skipped
+    fn exhausted(&self) -> PluginError {
+        match self.skipped.as_slice() {
+            [] => error!("No strategy available to route the payment"),
+            [skipped] => error!("{}", skipped.reason),
+            skipped => error!(
+                "No strategy found a route: {}",
+                skipped
+                    .iter()
+                    .map(|skipped| format!("{}: {}", skipped.strategy, skipped.reason))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+use serde_json as json;
+use serde_json::Value;
+
+use clightningrpc_plugin::error;
+use clightningrpc_plugin::errors::PluginError;
+use clightningrpc_plugin::plugin::Plugin;
+
+use crate::plugin::State;
+
+/// A routing strategy available in Barq
+#[derive(Deserialize, Serialize)]
+pub struct StrategyInfo {
+    pub name: String,
+    pub description: String,
+    /// Whether the strategy routes on the gossip map instead of `listchannels`
+    pub needs_p2p_info: bool,
+}
+
+/// Response payload for Barq list strategies RPC method
+#[derive(Deserialize, Serialize)]
+pub struct BarqListStrategiesResponse {
+    pub strategies: Vec<StrategyInfo>,
+}
+
+/// Barq RPC method to list the registered routing strategies
+pub fn barq_list_strategies(plugin: &mut Plugin<State>, _: Value) -> Result<Value, PluginError> {
+    let strategies = plugin
+        .state
+        .registry
+        .entries()
+        .map(|entry| StrategyInfo {
+            name: entry.name.clone(),
+            description: entry.description.clone(),
+            needs_p2p_info: entry.needs_p2p_info,
+        })
+        .collect();
+    let response = BarqListStrategiesResponse { strategies };
+    json::to_value(response).map_err(|err| error!("{err}"))
+}
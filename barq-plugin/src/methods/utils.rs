@@ -0,0 +1,174 @@
+//! Helpers shared between the Barq RPC methods
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use clightningrpc_plugin::error;
+use clightningrpc_plugin::errors::PluginError;
+
+use barq_common::amount::Msat;
+use barq_common::graph::NetworkGraph;
+use barq_common::inflight::PartId;
+use barq_common::node_id::NodeId;
+use barq_common::registry::{StrategyContext, StrategyEntry};
+use barq_common::short_channel_id::ShortChannelId;
+use barq_common::strategy::{RouteConstraints, Strategy};
+use barq_common::Network;
+
+use crate::plugin::State;
+
+/// Response from `getinfo` RPC command of Core Lightning
+///
+/// See: https://docs.corelightning.org/reference/lightning-getinfo#return-value
+#[derive(Debug, Deserialize)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    /// Represents the type of network on the node are working
+    pub network: String,
+    pub blockheight: u64,
+}
+
+/// Single entry of the `listsendpays` RPC command of Core Lightning
+///
+/// See: https://docs.corelightning.org/reference/lightning-listsendpays#return-value
+#[derive(Debug, Deserialize)]
+struct PendingSendpay {
+    payment_hash: String,
+    groupid: u64,
+    /// Missing for the payments sent in a single part
+    #[serde(default)]
+    partid: u64,
+}
+
+/// Response from `listsendpays` RPC command of Core Lightning
+#[derive(Debug, Deserialize)]
+struct PendingSendpays {
+    payments: Vec<PendingSendpay>,
+}
+
+/// Route constraints accepted by the Barq RPC methods, named after the
+/// parameters of CLN `pay`
+///
+/// See: https://docs.corelightning.org/reference/lightning-pay
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ConstraintsRequest {
+    /// Maximum total fee in msat
+    #[serde(default)]
+    pub maxfee: Option<Msat>,
+    /// Maximum total fee as a percentage of the amount
+    #[serde(default)]
+    pub maxfeepercent: Option<f64>,
+    /// Maximum number of blocks the payment can be locked
+    #[serde(default)]
+    pub maxdelay: Option<u64>,
+    /// Maximum number of hops of each part
+    #[serde(default)]
+    pub maxhops: Option<usize>,
+    /// Node ids and short channel ids to avoid
+    ///
+    /// A direction suffix (`/0` or `/1`) is accepted, but the whole channel
+    /// is excluded.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Short channel id of the channel every part must start with
+    #[serde(default)]
+    pub first_hop: Option<ShortChannelId>,
+    /// Node id of the node every part must reach the destination through
+    #[serde(default)]
+    pub last_hop: Option<NodeId>,
+}
+
+impl ConstraintsRequest {
+    /// Build the constraints that the strategies must honour
+    ///
+    /// Fails if one of the excluded nodes or channels is not valid.
+    pub fn constraints(&self) -> Result<RouteConstraints, PluginError> {
+        let mut constraints = RouteConstraints {
+            max_fee_msat: self.maxfee,
+            max_fee_percent: self.maxfeepercent,
+            max_cltv: self.maxdelay,
+            max_hops: self.maxhops,
+            first_hop: self.first_hop,
+            last_hop: self.last_hop,
+            ..RouteConstraints::default()
+        };
+        for exclude in &self.exclude {
+            // A node id is 33 bytes long, hex encoded
+            if exclude.len() == 66 {
+                let node = exclude.parse().map_err(|err| error!("{err}"))?;
+                constraints.excluded_nodes.insert(node);
+            } else {
+                let channel = exclude.split('/').next().unwrap_or(exclude);
+                let channel = channel.parse().map_err(|err| error!("{err}"))?;
+                constraints.excluded_channels.insert(channel);
+            }
+        }
+        Ok(constraints)
+    }
+}
+
+/// Call `getinfo` on the CLN node the plugin is attached to.
+pub fn get_node_info(state: &State) -> Result<NodeInfo, PluginError> {
+    state
+        .call("getinfo", serde_json::json!({}))
+        .map_err(|err| PluginError::new(err.code, &err.message, err.data))
+}
+
+/// Forget the parts in flight that CLN does not report as pending anymore
+///
+/// A part is tracked until `barqpay` sees its outcome, which never happens if
+/// the call is interrupted.
+pub fn prune_inflight(state: &State) -> Result<(), PluginError> {
+    if state.inflight.is_empty() {
+        return Ok(());
+    }
+    // The parts sent while we list the pending ones are not pruned
+    let generation = state.inflight.generation();
+    let pending: PendingSendpays = state
+        .call(
+            "listsendpays",
+            serde_json::json!({
+                "status": "pending",
+            }),
+        )
+        .map_err(|err| PluginError::new(err.code, &err.message, err.data))?;
+    let pending = pending
+        .payments
+        .into_iter()
+        .map(|payment| PartId {
+            payment_hash: payment.payment_hash,
+            groupid: payment.groupid,
+            partid: payment.partid,
+        })
+        .collect::<HashSet<_>>();
+    state.inflight.retain(generation, |id| pending.contains(id));
+    Ok(())
+}
+
+/// Get the network graph required by the given strategy from the cache.
+///
+/// If the strategy needs peer-to-peer information, the network graph is built
+/// from the gossip map. Else, the network graph is built from `listchannels`.
+pub fn build_network_graph(
+    state: &State,
+    strategy: &StrategyEntry,
+) -> Result<Arc<dyn NetworkGraph>, PluginError> {
+    state.graphs.get(state, strategy.needs_p2p_info)
+}
+
+/// Build a new instance of the given strategy.
+pub fn build_strategy(
+    state: &State,
+    strategy: &StrategyEntry,
+    network: Network,
+) -> Box<dyn Strategy> {
+    // SAFETY: It is safe to unwrap here because the plugin init them always.
+    let context = StrategyContext {
+        network,
+        root_path: state.lightning_dir.clone().unwrap(),
+        scorer: state.scorer.clone().unwrap(),
+    };
+    strategy.build(&context)
+}
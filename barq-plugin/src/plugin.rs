@@ -1,5 +1,8 @@
 //! Barq Plugin implementation
 
+use std::sync::Arc;
+use std::time::Duration;
+
 use clightningrpc_common::errors::{Error, RpcError};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
@@ -11,7 +14,22 @@ use clightningrpc_plugin::errors::PluginError;
 use clightningrpc_plugin::plugin::Plugin;
 use clightningrpc_plugin_macros::{plugin, rpc_method};
 
+use barq_common::algorithms::probabilistic::scorer::ScorerStore;
+use barq_common::inflight::InFlightHtlcs;
+use barq_common::liquidity::{LiquidityStore, DEFAULT_HALF_LIFE};
+use barq_common::registry::StrategyRegistry;
+
 use crate::methods;
+use crate::methods::graph::cache::{spawn_refresh, GraphCache};
+
+/// Name of the plugin option setting how often (in seconds) the cached
+/// network graphs are refreshed
+const GRAPH_REFRESH_INTERVAL_OPT: &str = "barq-graph-refresh-interval";
+/// Default refresh interval of the cached network graphs, in seconds
+const DEFAULT_GRAPH_REFRESH_INTERVAL: u64 = 300;
+/// Name of the plugin option setting the half-life (in seconds) of the
+/// liquidity bounds learned from the payments
+const LIQUIDITY_HALF_LIFE_OPT: &str = "barq-liquidity-half-life";
 
 /// Barq Plugin State
 ///
@@ -24,6 +42,20 @@ pub(crate) struct State {
     /// eg. /home/user/.lightning/lightning-rpc
     pub(crate) cln_rpc_path: Option<String>,
     pub(crate) network: Option<String>,
+    /// CLN directory of the network, where Barq keeps its data
+    ///
+    /// eg. /home/user/.lightning/bitcoin
+    pub(crate) lightning_dir: Option<String>,
+    /// The LDK scorer, loaded once and shared by all the payments
+    pub(crate) scorer: Option<Arc<ScorerStore>>,
+    /// The liquidity bounds shared by all the strategies
+    pub(crate) liquidity: Option<Arc<LiquidityStore>>,
+    /// Routing strategies available to the RPC methods
+    pub(crate) registry: Arc<StrategyRegistry>,
+    /// Network graphs shared by the RPC methods
+    pub(crate) graphs: Arc<GraphCache>,
+    /// The parts sent by all the payments and not resolved yet
+    pub(crate) inflight: Arc<InFlightHtlcs>,
 }
 
 impl State {
@@ -32,6 +64,12 @@ impl State {
         State {
             cln_rpc_path: None,
             network: None,
+            lightning_dir: None,
+            scorer: None,
+            liquidity: None,
+            registry: Arc::new(StrategyRegistry::default()),
+            graphs: Arc::new(GraphCache::default()),
+            inflight: Arc::new(InFlightHtlcs::default()),
         }
     }
 
@@ -74,10 +112,26 @@ pub fn build_plugin() -> anyhow::Result<Plugin<State>> {
         notification: [],
         methods: [
             barq_pay,
+            barq_keysend,
             barq_route_info,
+            barq_list_strategies,
         ],
         hooks: [],
     };
+    plugin.add_opt(
+        GRAPH_REFRESH_INTERVAL_OPT,
+        "int",
+        Some(DEFAULT_GRAPH_REFRESH_INTERVAL.to_string()),
+        "How often (in seconds) Barq refreshes its cached network graphs, 0 to never refresh them",
+        false,
+    );
+    plugin.add_opt(
+        LIQUIDITY_HALF_LIFE_OPT,
+        "int",
+        Some(DEFAULT_HALF_LIFE.as_secs().to_string()),
+        "Time (in seconds) after which Barq forgets half of what it learned about the liquidity of a channel",
+        false,
+    );
     plugin.on_init(on_init);
     Ok(plugin)
 }
@@ -88,6 +142,27 @@ fn on_init(plugin: &mut Plugin<State>) -> Value {
     let rpc_file = format!("{}/{}", config.lightning_dir, config.rpc_file);
     plugin.state.network = Some(config.network);
     plugin.state.cln_rpc_path = Some(rpc_file);
+    plugin.state.scorer = Some(Arc::new(ScorerStore::new(&config.lightning_dir)));
+    let half_life = plugin
+        .get_opt::<u64>(LIQUIDITY_HALF_LIFE_OPT)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_HALF_LIFE);
+    plugin.state.liquidity = Some(Arc::new(LiquidityStore::load(
+        &config.lightning_dir,
+        half_life,
+    )));
+    plugin.state.lightning_dir = Some(config.lightning_dir);
+
+    // Load the network graphs once, and keep them fresh in the background.
+    // Without refresh, they are loaded by the first payment.
+    let interval = plugin
+        .get_opt::<u64>(GRAPH_REFRESH_INTERVAL_OPT)
+        .unwrap_or(DEFAULT_GRAPH_REFRESH_INTERVAL);
+    if interval == 0 {
+        log::info!("the network graphs are never refreshed, `{GRAPH_REFRESH_INTERVAL_OPT}` is 0");
+    } else {
+        spawn_refresh(plugin.state.clone(), Duration::from_secs(interval));
+    }
 
     serde_json::json!({})
 }
@@ -97,6 +172,14 @@ pub fn barq_pay(plugin: &mut Plugin<State>, requet: Value) -> Result<Value, Plug
     methods::pay::barq_pay(plugin, requet)
 }
 
+#[rpc_method(
+    rpc_name = "barqkeysend",
+    description = "Send a keysend payment using Barq"
+)]
+fn barq_keysend(plugin: &mut Plugin<State>, request: Value) -> Result<Value, PluginError> {
+    methods::keysend::barq_keysend(plugin, request)
+}
+
 #[rpc_method(
     rpc_name = "barqrouteinfo",
     description = "Get route information using Barq"
@@ -104,3 +187,11 @@ pub fn barq_pay(plugin: &mut Plugin<State>, requet: Value) -> Result<Value, Plug
 fn barq_route_info(plugin: &mut Plugin<State>, request: Value) -> Result<Value, PluginError> {
     methods::route_info::barq_route_info(plugin, request)
 }
+
+#[rpc_method(
+    rpc_name = "barqliststrategies",
+    description = "List the routing strategies available in Barq"
+)]
+fn barq_list_strategies(plugin: &mut Plugin<State>, request: Value) -> Result<Value, PluginError> {
+    methods::strategies::barq_list_strategies(plugin, request)
+}
@@ -2,7 +2,7 @@ import os
 import pytest
 
 from pyln.testing.fixtures import *  # noqa: F403
-from pyln.testing.utils import only_one
+from pyln.testing.utils import only_one, wait_for
 from pyln.client import Millisatoshi
 from pyln.client import RpcError
 
@@ -54,6 +54,34 @@ def test_pay_without_amounts(node_factory):
     assert invoice['status'] == 'paid'
 
 
+def test_route_info(node_factory):
+    """Check that we can inspect the route without paying"""
+    l1, l2 = node_factory.line_graph(2, opts=[{"plugin": barq_binary }, { "plugin": barq_binary}], wait_for_announce=True)
+
+    route = l1.rpc.call("barqrouteinfo", {"dest_pubkey": l2.info["id"], "amount_msat": 123000, "cltv": 18})
+
+    assert route["hop_count"] == 1
+    assert route["total_fee_msat"] == 0
+    assert route["total_cltv"] == 18
+    part = only_one(route["route_info"])
+    assert part["amount_msat"] == 123000
+    hop = only_one(part["path"])
+    assert hop["id"] == l2.info["id"]
+    assert hop["amount_msat"] == 123000
+
+
+def test_route_info_auto(node_factory):
+    """Check that the auto mode falls back to a multi-hop strategy"""
+    l1, l2, l3 = node_factory.line_graph(3, opts=[{"plugin": barq_binary }, { "plugin": barq_binary}, { "plugin": barq_binary}], wait_for_announce=True)
+
+    route = l1.rpc.call("barqrouteinfo", {"dest_pubkey": l3.info["id"], "amount_msat": 123000, "cltv": 18, "strategy": "auto"})
+
+    assert route["strategy"] == "dijkstra"
+    skipped = only_one(route["skipped"])
+    assert skipped["strategy"] == "direct"
+    assert route["hop_count"] == 2
+
+
 def test_pay_fail_when_there_is_no_channel(node_factory):
     """We make sure that our is not able to pay an invoice when there is no channel"""
     l1 = node_factory.get_node(
@@ -81,6 +109,95 @@ def test_pay_fail_when_there_is_no_channel(node_factory):
     invoice = only_one(l2.rpc.listinvoices('test_pay_amounts')['invoices'])
     assert invoice['status'] == 'unpaid'
 
+
+def test_pay_with_dijkstra(node_factory):
+    """Pay a node we do not have a channel with"""
+    l1, l2, l3 = node_factory.line_graph(3, opts=[{"plugin": barq_binary }, { "plugin": barq_binary}, { "plugin": barq_binary}], wait_for_announce=True)
+    inv = l3.rpc.invoice(Millisatoshi("123sat"), 'test_pay_with_dijkstra', 'description')['bolt11']
+
+    route = l1.rpc.call("barqrouteinfo", {"dest_pubkey": l3.info["id"], "amount_msat": 123000, "cltv": 18, "strategy": "dijkstra"})
+    assert route["hop_count"] == 2
+
+    # Node ids are parsed regardless of their case, and bad ones rejected
+    route = l1.rpc.call("barqrouteinfo", {"dest_pubkey": l3.info["id"].upper(), "amount_msat": 123000, "cltv": 18, "strategy": "dijkstra"})
+    assert route["hop_count"] == 2
+    with pytest.raises(RpcError):
+        l1.rpc.call("barqrouteinfo", {"dest_pubkey": l3.info["id"][:-2], "amount_msat": 123000, "cltv": 18, "strategy": "dijkstra"})
+
+    l1.rpc.call("barqpay", {"bolt11_invoice": inv, "strategy": "dijkstra"})
+
+    invoice = only_one(l3.rpc.listinvoices('test_pay_with_dijkstra')['invoices'])
+    assert invoice['status'] == 'paid'
+
+
+def test_pay_respects_maxfee(node_factory):
+    """We never pay more fee than the one allowed"""
+    l1, l2, l3 = node_factory.line_graph(3, opts=[{"plugin": barq_binary }, { "plugin": barq_binary}, { "plugin": barq_binary}], wait_for_announce=True)
+    inv = l3.rpc.invoice(Millisatoshi("123sat"), 'test_pay_respects_maxfee', 'description')['bolt11']
+
+    with pytest.raises(RpcError):
+        l1.rpc.call("barqpay", {"bolt11_invoice": inv, "strategy": "dijkstra", "maxfee": 1})
+    with pytest.raises(RpcError):
+        l1.rpc.call("barqpay", {"bolt11_invoice": inv, "strategy": "dijkstra", "exclude": [l2.info["id"]]})
+
+    invoice = only_one(l3.rpc.listinvoices('test_pay_respects_maxfee')['invoices'])
+    assert invoice['status'] == 'unpaid'
+
+
+def test_pay_private_destination(node_factory, bitcoind):
+    """Pay a node only reachable through the route hints of its invoice"""
+    l1, l2 = node_factory.line_graph(2, opts=[{"plugin": barq_binary }, { "plugin": barq_binary}], wait_for_announce=True)
+    l3 = node_factory.get_node(options={"plugin": barq_binary})
+    l2.rpc.connect(l3.info["id"], "localhost", l3.port)
+    scid, _ = l2.fundchannel(l3, 10**6, announce_channel=False)
+    # l3 needs the policy of l2 to hint the channel in its invoice
+    wait_for(lambda: len(l3.rpc.listchannels(scid)["channels"]) == 2)
+
+    inv = l3.rpc.invoice(Millisatoshi("123sat"), 'test_pay_private_destination', 'description')['bolt11']
+    assert "routes" in l1.rpc.decodepay(inv)
+
+    l1.rpc.call("barqpay", {"bolt11_invoice": inv, "strategy": "dijkstra"})
+
+    invoice = only_one(l3.rpc.listinvoices('test_pay_private_destination')['invoices'])
+    assert invoice['status'] == 'paid'
+
+
+def test_pay_offer(node_factory):
+    """Pay a BOLT12 offer, through the blinded path of its invoice"""
+    l1, l2 = node_factory.line_graph(2, opts=[{"plugin": barq_binary }, { "plugin": barq_binary}], wait_for_announce=True)
+    offer = l2.rpc.offer(Millisatoshi("123sat"), 'test_pay_offer')['bolt12']
+
+    # The offer has an amount already
+    with pytest.raises(RpcError):
+        l1.rpc.call("barqpay", {"invoice": offer, "amount_msat": 123000})
+    l1.rpc.call("barqpay", {"invoice": offer, "strategy": "dijkstra"})
+
+    invoice = only_one(l2.rpc.listinvoices()['invoices'])
+    assert invoice['status'] == 'paid'
+    assert invoice['amount_received_msat'] >= Millisatoshi(123000)
+
+
+def test_keysend(node_factory):
+    """Send a keysend payment, with a custom record"""
+    l1, l2, l3 = node_factory.line_graph(3, opts=[{"plugin": barq_binary }, { "plugin": barq_binary}, { "plugin": barq_binary}], wait_for_announce=True)
+
+    # The TLV types below 2^16 are not custom records
+    with pytest.raises(RpcError):
+        l1.rpc.call("barqkeysend", {"destination": l3.info["id"], "amount_msat": 123000, "extratlvs": {"8": "00"}})
+
+    result = l1.rpc.call("barqkeysend", {"destination": l3.info["id"], "amount_msat": 123000, "strategy": "dijkstra", "extratlvs": {"133773310": "cafe"}})
+    assert result["status"] == "success"
+
+    invoice = only_one(l3.rpc.listinvoices(payment_hash=result["payment_hash"])['invoices'])
+    assert invoice['status'] == 'paid'
+    assert invoice['amount_received_msat'] == Millisatoshi(123000)
+    assert invoice['payment_preimage'] == result["payment_preimage"]
+
+    # The strategies splitting payments send a keysend in a single part
+    result = l1.rpc.call("barqkeysend", {"destination": l3.info["id"], "amount_msat": 500000, "strategy": "mincostflow"})
+    assert len(result["parts"]) == 1
+
+
 @pytest.mark.skip(reason="We need to implement the probabilistic strategy")
 def test_pay_with_ldk_algo(node_factory):
     """Try LDK algorithm"""